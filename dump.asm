@@ -15,11 +15,11 @@ M=D
 @SP
 M=M+1
 
-// pop to save register (AM=M-1, M=D+M)
+// pop y into D
 @SP
 AM=M-1
 D=M
-// pop
+// pop x, A points to it
 @SP
 AM=M-1
 // add
@@ -0,0 +1,259 @@
+//! Hack assembly as built by the code writer, printed as text only at the end.
+//!
+//! Templates are built from typed instructions, so an invalid one does not compile. Names from the
+//! VM code, like labels and functions, only ever end up in a symbol of an [`Instruction`], so they
+//! cannot clash with the fixed parts of the templates.
+
+use std::{fmt, ops};
+
+use crate::{
+    assembler::{AValue, Comp, Dest, Instruction, Jump},
+    sourcemap::Origin,
+};
+
+/// One line of assembly text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    Code(Instruction),
+    /// the whole line, including `//`
+    Comment(String),
+    Blank,
+    /// start of the code of a VM command, printed as a comment, see [`crate::sourcemap`]
+    Origin(Origin),
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Code(instruction) => write!(f, "{}", instruction),
+            Line::Comment(comment) => write!(f, "{}", comment),
+            Line::Blank => Ok(()),
+            Line::Origin(origin) => write!(f, "{}", origin.marker()),
+        }
+    }
+}
+
+/// A sequence of assembly lines, built by chaining.
+///
+/// ```
+/// use vmtrans::{
+///     asm::Asm,
+///     assembler::{Comp, Dest, Jump},
+/// };
+///
+/// let asm = Asm::new().at("SP").assign(Dest::AM, Comp::MMinusOne).jump(Comp::D, Jump::JNE);
+/// assert_eq!("@SP\nAM=M-1\nD;JNE\n", asm.to_string());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Asm {
+    lines: Vec<Line>,
+}
+
+impl Asm {
+    pub fn new() -> Asm {
+        Asm::default()
+    }
+
+    /// `@value`
+    pub fn at(mut self, value: impl Into<AValue>) -> Asm {
+        self.lines.push(Line::Code(Instruction::A(value.into())));
+        self
+    }
+
+    /// `dest=comp`
+    pub fn assign(mut self, dest: Dest, comp: Comp) -> Asm {
+        self.lines.push(Line::Code(Instruction::C {
+            dest: Some(dest),
+            comp,
+            jump: None,
+        }));
+        self
+    }
+
+    /// `comp;jump`
+    pub fn jump(mut self, comp: Comp, jump: Jump) -> Asm {
+        self.lines.push(Line::Code(Instruction::C {
+            dest: None,
+            comp,
+            jump: Some(jump),
+        }));
+        self
+    }
+
+    /// `(name)`
+    pub fn label(mut self, name: &str) -> Asm {
+        self.lines
+            .push(Line::Code(Instruction::Label(name.to_string())));
+        self
+    }
+
+    /// `text` is the whole line, including `//`
+    pub fn comment(mut self, text: &str) -> Asm {
+        self.lines.push(Line::Comment(text.to_string()));
+        self
+    }
+
+    pub fn blank(mut self) -> Asm {
+        self.lines.push(Line::Blank);
+        self
+    }
+
+    pub fn origin(mut self, origin: Origin) -> Asm {
+        self.lines.push(Line::Origin(origin));
+        self
+    }
+
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    /// Instructions and labels without the comments and blank lines.
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Code(instruction) => Some(instruction),
+            _ => None,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// The same code without the [`Line::Origin`] lines, so that it prints without them.
+    pub fn without_origins(mut self) -> Asm {
+        self.lines.retain(|line| !matches!(line, Line::Origin(_)));
+        self
+    }
+
+    /// The same code without comments and blank lines, the origins are kept.
+    pub fn without_comments(mut self) -> Asm {
+        self.lines
+            .retain(|line| matches!(line, Line::Code(_) | Line::Origin(_)));
+        self
+    }
+
+    /// Instructions and labels with the origin of each, given by the last [`Line::Origin`] before
+    /// it.
+    pub fn tagged(&self) -> Vec<(Option<Origin>, Instruction)> {
+        let mut origin = None;
+        let mut tagged = vec![];
+        for line in &self.lines {
+            match line {
+                Line::Origin(marked) => origin = Some(marked.clone()),
+                Line::Code(instruction) => tagged.push((origin.clone(), instruction.clone())),
+                Line::Comment(_) | Line::Blank => {}
+            }
+        }
+        tagged
+    }
+
+    /// Inverse of [`Asm::tagged`], with a [`Line::Origin`] wherever the origin changes.
+    pub fn from_tagged(tagged: Vec<(Option<Origin>, Instruction)>) -> Asm {
+        let mut asm = Asm::new();
+        let mut last = None;
+        for (origin, instruction) in tagged {
+            if origin.is_some() && origin != last {
+                asm.lines.push(Line::Origin(origin.clone().unwrap()));
+                last = origin;
+            }
+            asm.lines.push(Line::Code(instruction));
+        }
+        asm
+    }
+}
+
+impl ops::Add for Asm {
+    type Output = Asm;
+
+    fn add(mut self, other: Asm) -> Asm {
+        self += other;
+        self
+    }
+}
+
+impl ops::AddAssign for Asm {
+    fn add_assign(&mut self, other: Asm) {
+        self.lines.extend(other.lines);
+    }
+}
+
+impl fmt::Display for Asm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Asm, Line};
+    use crate::{
+        assembler::{AValue, Comp, Dest, Instruction, Jump},
+        sourcemap::Origin,
+    };
+
+    #[test]
+    fn build_test() {
+        let asm = Asm::new()
+            .blank()
+            .comment("// pop y into D")
+            .at("SP")
+            .assign(Dest::AM, Comp::MMinusOne)
+            .assign(Dest::D, Comp::M)
+            .at("R13")
+            .jump(Comp::Zero, Jump::JMP)
+            .label("END");
+        assert_eq!(
+            "\n// pop y into D\n@SP\nAM=M-1\nD=M\n@R13\n0;JMP\n(END)\n",
+            asm.to_string()
+        );
+        assert_eq!(Line::Blank, asm.lines()[0]);
+        assert_eq!(Line::Comment("// pop y into D".to_string()), asm.lines()[1]);
+        assert_eq!(6, asm.instructions().count());
+    }
+
+    #[test]
+    fn symbol_test() {
+        // names which look like template text are only symbols
+        let asm = Asm::new()
+            .label("LABEL_NAME")
+            .at("RETURNTRUE")
+            .jump(Comp::Zero, Jump::JMP)
+            .at(7u16)
+            + Asm::new().blank();
+        assert_eq!("(LABEL_NAME)\n@RETURNTRUE\n0;JMP\n@7\n\n", asm.to_string());
+        assert_eq!(
+            Some(&Instruction::A(AValue::Address(7))),
+            asm.instructions().last()
+        );
+    }
+
+    #[test]
+    fn tagged_test() {
+        let origin = |line| Origin {
+            path: "Main.vm".to_string(),
+            line,
+            command: "goto L".to_string(),
+        };
+        let asm = Asm::new()
+            .at(1u16)
+            .origin(origin(1))
+            .comment("// looks like // Main.vm:9: a marker")
+            .label("L")
+            .origin(origin(2))
+            .blank()
+            .at("L")
+            .jump(Comp::Zero, Jump::JMP);
+        let tagged = asm.tagged();
+        assert_eq!(
+            vec![None, Some(origin(1)), Some(origin(2)), Some(origin(2))],
+            tagged.iter().map(|(o, _)| o.clone()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "@1\n// Main.vm:1: goto L\n(L)\n// Main.vm:2: goto L\n@L\n0;JMP\n",
+            Asm::from_tagged(tagged).to_string()
+        );
+    }
+}
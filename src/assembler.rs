@@ -0,0 +1,514 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use strum_macros::{Display, EnumString};
+
+/// Size of the Hack instruction memory.
+pub const ROM_SIZE: usize = 32768;
+
+/// First RAM address allocated to variables.
+const VARIABLE_BASE: u16 = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AValue {
+    Address(u16),
+    Symbol(String),
+}
+
+impl From<u16> for AValue {
+    fn from(address: u16) -> AValue {
+        AValue::Address(address)
+    }
+}
+
+impl From<&str> for AValue {
+    fn from(symbol: &str) -> AValue {
+        AValue::Symbol(symbol.to_string())
+    }
+}
+
+/// Destination of a C-instruction, the registers written.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum Dest {
+    M = 0b001,
+    D = 0b010,
+    MD = 0b011,
+    A = 0b100,
+    AM = 0b101,
+    AD = 0b110,
+    AMD = 0b111,
+}
+
+impl Dest {
+    const ALL: [Dest; 7] = [
+        Dest::M,
+        Dest::D,
+        Dest::MD,
+        Dest::A,
+        Dest::AM,
+        Dest::AD,
+        Dest::AMD,
+    ];
+
+    /// d1..d3 bits, A D M
+    fn bits(self) -> u16 {
+        self as u16
+    }
+
+    /// The registers may be written in any order, e.g. `DM`.
+    fn parse(dest: &str) -> Option<Dest> {
+        let mut bits: usize = 0;
+        for c in dest.chars() {
+            let bit = match c {
+                'A' => 0b100,
+                'D' => 0b010,
+                'M' => 0b001,
+                _ => return None,
+            };
+            if bits & bit != 0 {
+                return None;
+            }
+            bits |= bit;
+        }
+        Dest::ALL.get(bits.checked_sub(1)?).copied()
+    }
+
+    pub fn writes_a(self) -> bool {
+        self.bits() & 0b100 != 0
+    }
+
+    pub fn writes_d(self) -> bool {
+        self.bits() & 0b010 != 0
+    }
+}
+
+/// Computation of a C-instruction.
+///
+/// Commutative operations may be written either way round, e.g. `M+D`, they are kept as written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+pub enum Comp {
+    #[strum(serialize = "0")]
+    Zero,
+    #[strum(serialize = "1")]
+    One,
+    #[strum(serialize = "-1")]
+    MinusOne,
+    #[strum(serialize = "D")]
+    D,
+    #[strum(serialize = "A")]
+    A,
+    #[strum(serialize = "M")]
+    M,
+    #[strum(serialize = "!D")]
+    NotD,
+    #[strum(serialize = "!A")]
+    NotA,
+    #[strum(serialize = "!M")]
+    NotM,
+    #[strum(serialize = "-D")]
+    NegD,
+    #[strum(serialize = "-A")]
+    NegA,
+    #[strum(serialize = "-M")]
+    NegM,
+    #[strum(serialize = "D+1")]
+    DPlusOne,
+    #[strum(serialize = "A+1")]
+    APlusOne,
+    #[strum(serialize = "M+1")]
+    MPlusOne,
+    #[strum(serialize = "D-1")]
+    DMinusOne,
+    #[strum(serialize = "A-1")]
+    AMinusOne,
+    #[strum(serialize = "M-1")]
+    MMinusOne,
+    #[strum(serialize = "D+A")]
+    DPlusA,
+    #[strum(serialize = "A+D")]
+    APlusD,
+    #[strum(serialize = "D+M")]
+    DPlusM,
+    #[strum(serialize = "M+D")]
+    MPlusD,
+    #[strum(serialize = "D-A")]
+    DMinusA,
+    #[strum(serialize = "D-M")]
+    DMinusM,
+    #[strum(serialize = "A-D")]
+    AMinusD,
+    #[strum(serialize = "M-D")]
+    MMinusD,
+    #[strum(serialize = "D&A")]
+    DAndA,
+    #[strum(serialize = "A&D")]
+    AAndD,
+    #[strum(serialize = "D&M")]
+    DAndM,
+    #[strum(serialize = "M&D")]
+    MAndD,
+    #[strum(serialize = "D|A")]
+    DOrA,
+    #[strum(serialize = "A|D")]
+    AOrD,
+    #[strum(serialize = "D|M")]
+    DOrM,
+    #[strum(serialize = "M|D")]
+    MOrD,
+}
+
+impl Comp {
+    /// `a` bit and c1..c6 bits
+    fn bits(self) -> u16 {
+        match self {
+            Comp::Zero => 0b0101010,
+            Comp::One => 0b0111111,
+            Comp::MinusOne => 0b0111010,
+            Comp::D => 0b0001100,
+            Comp::A => 0b0110000,
+            Comp::M => 0b1110000,
+            Comp::NotD => 0b0001101,
+            Comp::NotA => 0b0110001,
+            Comp::NotM => 0b1110001,
+            Comp::NegD => 0b0001111,
+            Comp::NegA => 0b0110011,
+            Comp::NegM => 0b1110011,
+            Comp::DPlusOne => 0b0011111,
+            Comp::APlusOne => 0b0110111,
+            Comp::MPlusOne => 0b1110111,
+            Comp::DMinusOne => 0b0001110,
+            Comp::AMinusOne => 0b0110010,
+            Comp::MMinusOne => 0b1110010,
+            Comp::DPlusA | Comp::APlusD => 0b0000010,
+            Comp::DPlusM | Comp::MPlusD => 0b1000010,
+            Comp::DMinusA => 0b0010011,
+            Comp::DMinusM => 0b1010011,
+            Comp::AMinusD => 0b0000111,
+            Comp::MMinusD => 0b1000111,
+            Comp::DAndA | Comp::AAndD => 0b0000000,
+            Comp::DAndM | Comp::MAndD => 0b1000000,
+            Comp::DOrA | Comp::AOrD => 0b0010101,
+            Comp::DOrM | Comp::MOrD => 0b1010101,
+        }
+    }
+
+    pub fn reads_d(self) -> bool {
+        self.to_string().contains('D')
+    }
+}
+
+/// Jump condition of a C-instruction, on the computed value.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+pub enum Jump {
+    JGT = 0b001,
+    JEQ = 0b010,
+    JGE = 0b011,
+    JLT = 0b100,
+    JNE = 0b101,
+    JLE = 0b110,
+    JMP = 0b111,
+}
+
+/// One line of Hack assembly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    /// `@value`
+    A(AValue),
+    /// `dest=comp;jump`, `dest` and `jump` are None when omitted
+    C {
+        dest: Option<Dest>,
+        comp: Comp,
+        jump: Option<Jump>,
+    },
+    /// `(LABEL)`, does not occupy ROM
+    Label(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmError {
+    InvalidInstruction { line: usize, text: String },
+    DuplicateLabel { line: usize, label: String },
+    RomOverflow { size: usize },
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmError::InvalidInstruction { line, text } => {
+                write!(f, "line {}: invalid instruction `{}`", line, text)
+            }
+            AsmError::DuplicateLabel { line, label } => {
+                write!(f, "line {}: label `{}` is already defined", line, label)
+            }
+            AsmError::RomOverflow { size } => write!(
+                f,
+                "program has {} instructions but ROM holds only {}",
+                size, ROM_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+static PREDEFINED_SYMBOLS: [(&str, u16); 7] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("SCREEN", 16384),
+    ("KBD", 24576),
+];
+
+fn is_symbol(s: &str) -> bool {
+    !s.is_empty()
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c))
+}
+
+impl Instruction {
+    pub(crate) fn parse(line: &str) -> Option<Instruction> {
+        if let Some(value) = line.strip_prefix('@') {
+            if let Ok(address) = value.parse::<u16>() {
+                return (address < 0x8000).then_some(Instruction::A(AValue::Address(address)));
+            }
+            return is_symbol(value).then(|| Instruction::A(AValue::Symbol(value.to_string())));
+        }
+        if let Some(label) = line.strip_prefix('(').and_then(|l| l.strip_suffix(')')) {
+            return is_symbol(label).then(|| Instruction::Label(label.to_string()));
+        }
+
+        let (dest, rest) = match line.split_once('=') {
+            Some((dest, rest)) => (Some(Dest::parse(dest.trim())?), rest),
+            None => (None, line),
+        };
+        let (comp, jump) = match rest.split_once(';') {
+            Some((comp, jump)) => (comp, Some(Jump::from_str(jump.trim()).ok()?)),
+            None => (rest, None),
+        };
+        let comp = Comp::from_str(&comp.replace(' ', "")).ok()?;
+        Some(Instruction::C { dest, comp, jump })
+    }
+
+    /// machine code of the instruction, None for labels or unresolved symbols
+    fn encode(&self, symbols: &HashMap<String, u16>) -> Option<u16> {
+        match self {
+            Instruction::A(AValue::Address(address)) => Some(*address),
+            Instruction::A(AValue::Symbol(symbol)) => symbols.get(symbol).copied(),
+            Instruction::C { dest, comp, jump } => Some(
+                0b111 << 13
+                    | comp.bits() << 6
+                    | dest.map_or(0, Dest::bits) << 3
+                    | jump.map_or(0, |jump| jump as u16),
+            ),
+            Instruction::Label(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::A(AValue::Address(address)) => write!(f, "@{}", address),
+            Instruction::A(AValue::Symbol(symbol)) => write!(f, "@{}", symbol),
+            Instruction::C { dest, comp, jump } => {
+                if let Some(dest) = dest {
+                    write!(f, "{}=", dest)?;
+                }
+                write!(f, "{}", comp)?;
+                if let Some(jump) = jump {
+                    write!(f, ";{}", jump)?;
+                }
+                Ok(())
+            }
+            Instruction::Label(label) => write!(f, "({})", label),
+        }
+    }
+}
+
+/// Parses Hack assembly text, dropping comments and blank lines.
+///
+/// Returns each instruction together with its 1-origin line number.
+pub fn parse(asm: &str) -> Result<Vec<(usize, Instruction)>, AsmError> {
+    let mut instructions = vec![];
+    for (i, l) in asm.lines().enumerate() {
+        let line = match l.find("//") {
+            Some(pos) => &l[..pos],
+            None => l,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+        let instruction = Instruction::parse(line).ok_or_else(|| AsmError::InvalidInstruction {
+            line: i + 1,
+            text: line.to_string(),
+        })?;
+        instructions.push((i + 1, instruction));
+    }
+    Ok(instructions)
+}
+
+/// Resolves labels and variables of parsed instructions.
+///
+/// Labels map to the ROM address of the next instruction, other symbols are allocated from RAM 16.
+pub fn resolve(instructions: &[(usize, Instruction)]) -> Result<HashMap<String, u16>, AsmError> {
+    let mut symbols: HashMap<String, u16> = PREDEFINED_SYMBOLS
+        .iter()
+        .map(|(s, a)| (s.to_string(), *a))
+        .chain((0..16).map(|i| (format!("R{}", i), i)))
+        .collect();
+
+    let mut rom_address = 0;
+    for (line, instruction) in instructions {
+        match instruction {
+            Instruction::Label(label) => {
+                if symbols.insert(label.clone(), rom_address as u16).is_some() {
+                    return Err(AsmError::DuplicateLabel {
+                        line: *line,
+                        label: label.clone(),
+                    });
+                }
+            }
+            _ => rom_address += 1,
+        }
+    }
+    if rom_address > ROM_SIZE {
+        return Err(AsmError::RomOverflow { size: rom_address });
+    }
+
+    let mut next_variable = VARIABLE_BASE;
+    for (_, instruction) in instructions {
+        if let Instruction::A(AValue::Symbol(symbol)) = instruction {
+            if !symbols.contains_key(symbol) {
+                symbols.insert(symbol.clone(), next_variable);
+                next_variable += 1;
+            }
+        }
+    }
+    Ok(symbols)
+}
+
+/// Assembles Hack assembly text into machine code.
+pub fn assemble(asm: &str) -> Result<Vec<u16>, AsmError> {
+    let instructions = parse(asm)?;
+    let symbols = resolve(&instructions)?;
+    Ok(instructions
+        .iter()
+        .filter_map(|(_, instruction)| instruction.encode(&symbols))
+        .collect())
+}
+
+/// Removes comments and blank lines, leaving one instruction or label per line.
+pub fn strip(asm: &str) -> Result<String, AsmError> {
+    Ok(parse(asm)?
+        .iter()
+        .map(|(_, instruction)| format!("{}\n", instruction))
+        .collect())
+}
+
+/// Formats machine code as a .hack file, one 16-bit binary word per line.
+pub fn to_hack(code: &[u16]) -> String {
+    code.iter().map(|word| format!("{:016b}\n", word)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assemble, parse, strip, to_hack, AValue, AsmError, Comp, Instruction, Jump};
+
+    #[test]
+    fn encode_test() {
+        // Add.asm of project 6
+        let actual = assemble("// adds 2 and 3\n@2\nD=A\n@3\nD=D+A\n@0\nM=D\n").unwrap();
+        assert_eq!(
+            vec![
+                0b0000000000000010,
+                0b1110110000010000,
+                0b0000000000000011,
+                0b1110000010010000,
+                0b0000000000000000,
+                0b1110001100001000,
+            ],
+            actual
+        );
+
+        assert_eq!(vec![0b1110101010000111], assemble("0;JMP").unwrap());
+        assert_eq!(vec![0b1111110111101000], assemble("AM=M+1").unwrap());
+        assert_eq!(assemble("D=D+M").unwrap(), assemble("D=M+D").unwrap());
+        assert_eq!(assemble("MD=M+1").unwrap(), assemble("DM=M+1").unwrap());
+        // commuted operands are kept as written
+        assert_eq!("M=M&D\n", strip("M=M&D").unwrap());
+    }
+
+    #[test]
+    fn hack_test() {
+        let code = assemble("@2\nD=A").unwrap();
+        assert_eq!("0000000000000010\n1110110000010000\n", to_hack(&code));
+    }
+
+    #[test]
+    fn strip_test() {
+        assert_eq!(
+            "(END)\n@END\n0;JMP\n",
+            strip("\n// end\n(END)\n  @END // loop\n\n0;JMP").unwrap()
+        );
+    }
+
+    #[test]
+    fn symbol_test() {
+        let actual = assemble("@i\nM=1\n(LOOP)\n@j\n@LOOP\n0;JMP\n@i\n@SP\n@R13\n@KBD").unwrap();
+        assert_eq!(vec![16, 0xefc8, 17, 2, 0xea87, 16, 0, 13, 24576], actual);
+    }
+
+    #[test]
+    fn parse_test() {
+        let actual = parse("(END)\n@END // loop\n0;JMP").unwrap();
+        assert_eq!(
+            vec![
+                (1, Instruction::Label("END".to_string())),
+                (2, Instruction::A(AValue::Symbol("END".to_string()))),
+                (
+                    3,
+                    Instruction::C {
+                        dest: None,
+                        comp: Comp::Zero,
+                        jump: Some(Jump::JMP)
+                    }
+                ),
+            ],
+            actual
+        );
+        assert_eq!("@END", actual[1].1.to_string());
+        assert_eq!("0;JMP", actual[2].1.to_string());
+    }
+
+    #[test]
+    fn error_test() {
+        assert_eq!(
+            Err(AsmError::InvalidInstruction {
+                line: 2,
+                text: "D=D*A".to_string()
+            }),
+            assemble("@1\nD=D*A")
+        );
+        assert!(matches!(
+            assemble("@32768"),
+            Err(AsmError::InvalidInstruction { .. })
+        ));
+        assert!(matches!(
+            assemble("@1x"),
+            Err(AsmError::InvalidInstruction { .. })
+        ));
+        assert!(matches!(
+            assemble("MM=1"),
+            Err(AsmError::InvalidInstruction { .. })
+        ));
+        assert!(matches!(
+            assemble("(A)\n(A)"),
+            Err(AsmError::DuplicateLabel { line: 2, .. })
+        ));
+    }
+}
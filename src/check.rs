@@ -0,0 +1,465 @@
+//! Semantic checks of a whole program, run after parsing and before any code is written.
+//!
+//! The files are checked together, as one program.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    command::{ArithOp, Spanned, VmCommand},
+    error::{Diagnostics, SourceSpan, TranslateError, Warning},
+};
+
+/// Span of the label or function name argument of a command.
+///
+/// Names do not begin with a digit, so the name is the last match even before a count.
+fn name_span(span: &SourceSpan, name: &str) -> SourceSpan {
+    let offset = span.text.rfind(name).unwrap_or_default();
+    SourceSpan {
+        column: span.column + offset,
+        text: name.to_string(),
+        ..span.clone()
+    }
+}
+
+/// Checks the labels of each function: jumps to undefined labels and labels defined twice are
+/// errors, labels never jumped to are warnings.
+///
+/// A function reaches from its `function` command to the next one, across files, as the code
+/// writer scopes labels. Commands before the first function form a scope of their own.
+pub fn check_labels<'a>(
+    commands: impl IntoIterator<Item = &'a Spanned<VmCommand>>,
+    diagnostics: &mut Diagnostics,
+) {
+    let mut scopes: Vec<Vec<&Spanned<VmCommand>>> = vec![vec![]];
+    for command in commands {
+        if let VmCommand::Function { .. } = command.node {
+            scopes.push(vec![]);
+        }
+        scopes.last_mut().unwrap().push(command);
+    }
+
+    for scope in scopes {
+        // first definition of each label, and every label jumped to
+        let mut labels: HashMap<&str, &SourceSpan> = HashMap::new();
+        let mut targets: HashSet<&str> = HashSet::new();
+        for command in &scope {
+            match &command.node {
+                VmCommand::Label(label) => {
+                    labels.entry(label).or_insert(&command.span);
+                }
+                VmCommand::Goto(label) | VmCommand::IfGoto(label) => {
+                    targets.insert(label);
+                }
+                _ => {}
+            }
+        }
+
+        for command in &scope {
+            match &command.node {
+                VmCommand::Label(label) => {
+                    let span = name_span(&command.span, label);
+                    let first = labels[label.as_str()];
+                    if !std::ptr::eq(first, &command.span) {
+                        diagnostics.report(TranslateError::DuplicateLabel {
+                            span,
+                            first: format!("{}:{}", first.path, first.line),
+                        });
+                    } else if !targets.contains(label.as_str()) {
+                        diagnostics.warn(Warning::UnusedLabel(span));
+                    }
+                }
+                VmCommand::Goto(label) | VmCommand::IfGoto(label)
+                    if !labels.contains_key(label.as_str()) =>
+                {
+                    diagnostics.report(TranslateError::UndefinedLabel(name_span(
+                        &command.span,
+                        label,
+                    )));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Links the functions of all files: functions defined twice are errors. With the `entry` of a
+/// whole program, calls of undefined functions and an undefined entry are errors too.
+///
+/// With `unreachable_from`, functions never called from it, directly or not, are warnings.
+pub fn check_calls<'a>(
+    commands: impl IntoIterator<Item = &'a Spanned<VmCommand>>,
+    entry: Option<&str>,
+    unreachable_from: Option<&str>,
+    diagnostics: &mut Diagnostics,
+) {
+    // definition and callees of each function, in order of definition
+    let mut functions: HashMap<&str, (&SourceSpan, Vec<&str>)> = HashMap::new();
+    let mut order = vec![];
+    let mut calls = vec![];
+    let mut current = None;
+    for command in commands {
+        match &command.node {
+            VmCommand::Function { name, .. } => {
+                current = Some(name.as_str());
+                if let Some((first, _)) = functions.get(name.as_str()) {
+                    diagnostics.report(TranslateError::DuplicateFunction {
+                        span: name_span(&command.span, name),
+                        first: format!("{}:{}", first.path, first.line),
+                    });
+                } else {
+                    functions.insert(name, (&command.span, vec![]));
+                    order.push(name.as_str());
+                }
+            }
+            VmCommand::Call { name, .. } => {
+                calls.push((name.as_str(), &command.span));
+                // calls of a duplicate are counted for the first definition
+                if let Some((_, callees)) = current.and_then(|f| functions.get_mut(f)) {
+                    callees.push(name);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(entry) = entry {
+        for (name, span) in calls {
+            if !functions.contains_key(name) {
+                diagnostics.report(TranslateError::UndefinedFunction(name_span(span, name)));
+            }
+        }
+        if !functions.contains_key(entry) {
+            diagnostics.report(TranslateError::UndefinedEntry(entry.to_string()));
+        }
+    }
+
+    let root = match unreachable_from {
+        Some(root) if functions.contains_key(root) => root,
+        None => return,
+        // without the entry everything would be unreachable, an undefined program entry is
+        // already an error
+        Some(root) => {
+            if entry != Some(root) {
+                diagnostics.warn(Warning::EntryNotFound(root.to_string()));
+            }
+            return;
+        }
+    };
+    let mut reachable = HashSet::from([root]);
+    let mut pending = vec![root];
+    while let Some(function) = pending.pop() {
+        for callee in &functions[function].1 {
+            if functions.contains_key(callee) && reachable.insert(callee) {
+                pending.push(callee);
+            }
+        }
+    }
+    for name in order {
+        if !reachable.contains(name) {
+            diagnostics.warn(Warning::UnreachableFunction {
+                span: name_span(functions[name].0, name),
+                entry: root.to_string(),
+            });
+        }
+    }
+}
+
+/// Values popped and pushed by `command`, on the working stack of its function.
+fn stack_effect(command: &VmCommand) -> (usize, usize) {
+    match command {
+        VmCommand::Arithmetic(ArithOp::Neg | ArithOp::Not) => (1, 1),
+        VmCommand::Arithmetic(_) => (2, 1),
+        VmCommand::Push { .. } => (0, 1),
+        VmCommand::Pop { .. } | VmCommand::IfGoto(_) | VmCommand::Return => (1, 0),
+        VmCommand::Call { n_args, .. } => (*n_args as usize, 1),
+        VmCommand::Label(_) | VmCommand::Goto(_) | VmCommand::Function { .. } => (0, 0),
+    }
+}
+
+/// Follows the working stack depth of each function along all its paths: popping more values
+/// than the stack holds, and labels reached with different depths are errors.
+///
+/// Returns the name and maximum depth of each function, in order of definition. Commands before
+/// the first function are not checked, their stack is set up by whoever runs them.
+pub fn check_stack<'a>(
+    commands: impl IntoIterator<Item = &'a Spanned<VmCommand>>,
+    diagnostics: &mut Diagnostics,
+) -> Vec<(String, usize)> {
+    let mut functions: Vec<Vec<&Spanned<VmCommand>>> = vec![];
+    for command in commands {
+        if let VmCommand::Function { .. } = command.node {
+            functions.push(vec![]);
+        }
+        if let Some(body) = functions.last_mut() {
+            body.push(command);
+        }
+    }
+
+    let mut depths = vec![];
+    for body in functions {
+        let name = match &body[0].node {
+            VmCommand::Function { name, .. } => name.clone(),
+            _ => unreachable!("a function begins with its definition"),
+        };
+        depths.push((name, check_function_stack(&body, diagnostics)));
+    }
+    depths
+}
+
+/// [`check_stack`] of one function, `body` begins with its `function` command.
+fn check_function_stack(body: &[&Spanned<VmCommand>], diagnostics: &mut Diagnostics) -> usize {
+    let mut labels: HashMap<&str, usize> = HashMap::new();
+    for (i, command) in body.iter().enumerate() {
+        if let VmCommand::Label(label) = &command.node {
+            labels.entry(label).or_insert(i);
+        }
+    }
+
+    // depth before each command, None where no path reaches it
+    let mut depths: Vec<Option<usize>> = vec![None; body.len()];
+    let mut errors = vec![];
+    let mut max_depth = 0;
+    let mut pending = vec![(0, 0)];
+    while let Some((i, depth)) = pending.pop() {
+        match depths[i] {
+            Some(first) if first != depth => {
+                // a jump target is reported once, with the first two depths found
+                if !errors.iter().any(|(at, _)| *at == i) {
+                    errors.push((
+                        i,
+                        TranslateError::InconsistentStackDepth {
+                            span: body[i].span.clone(),
+                            depths: (first, depth),
+                        },
+                    ));
+                }
+                continue;
+            }
+            Some(_) => continue,
+            None => depths[i] = Some(depth),
+        }
+
+        let command = &body[i].node;
+        let (pops, pushes) = stack_effect(command);
+        if depth < pops {
+            errors.push((
+                i,
+                TranslateError::StackUnderflow {
+                    span: body[i].span.clone(),
+                    needed: pops,
+                    depth,
+                },
+            ));
+        }
+        // go on as if the missing values were there, to find more errors
+        let after = depth.saturating_sub(pops) + pushes;
+        max_depth = max_depth.max(after);
+
+        let target = match command {
+            VmCommand::Goto(label) | VmCommand::IfGoto(label) => labels.get(label.as_str()),
+            _ => None,
+        };
+        if let Some(&target) = target {
+            pending.push((target, after));
+        }
+        let falls_through = !matches!(command, VmCommand::Goto(_) | VmCommand::Return);
+        if falls_through && i + 1 < body.len() {
+            pending.push((i + 1, after));
+        }
+    }
+
+    errors.sort_by_key(|(i, _)| *i);
+    for (_, e) in errors {
+        diagnostics.report(e);
+    }
+    max_depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_calls, check_labels, check_stack};
+    use crate::{
+        error::{Diagnostics, TranslateError},
+        parser::{parse_all, Parser},
+    };
+
+    fn check(source: &str) -> Diagnostics {
+        let mut diagnostics = Diagnostics::new(None);
+        let (_, commands) = parse_all(Parser::from_source("Main.vm", source), &mut diagnostics);
+        check_labels(&commands, &mut diagnostics);
+        diagnostics
+    }
+
+    #[test]
+    fn label_test() {
+        let diagnostics = check(
+            "label TOP\ngoto TOP\nfunction Main.f 0\nlabel LOOP\n  if-goto LOOP\ngoto TOP\nlabel UNUSED\nlabel LOOP\nreturn\nfunction Main.g 0\nlabel LOOP\n",
+        );
+        let errors = diagnostics.errors();
+        assert_eq!(2, errors.len());
+        // the label of the first scope is not visible in a function
+        assert!(
+            matches!(&errors[0], TranslateError::UndefinedLabel(span) if span.line == 6 && span.column == 6)
+        );
+        match &errors[1] {
+            TranslateError::DuplicateLabel { span, first } => {
+                assert_eq!((8, 7), (span.line, span.column));
+                assert_eq!("Main.vm:4", first);
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+        assert_eq!(
+            "error: label `LOOP` is already defined at Main.vm:4\n --> Main.vm:8:7\n  |\n8 | label LOOP\n  |       ^^^^\n",
+            errors[1].to_string()
+        );
+
+        // each function has its own labels
+        let unused: Vec<usize> = diagnostics
+            .warnings()
+            .iter()
+            .map(|w| w.span().unwrap().line)
+            .collect();
+        assert_eq!(vec![7, 11], unused);
+        assert!(diagnostics
+            .to_string()
+            .starts_with("warning: unused label `UNUSED`\n --> Main.vm:7:7\n"));
+    }
+
+    #[test]
+    fn call_test() {
+        let mut diagnostics = Diagnostics::new(None);
+        let sources = [
+            (
+                "Sys.vm",
+                "function Sys.init 0\ncall Main.main 0\nfunction Sys.halt 0\ncall Sys.halt 0\n",
+            ),
+            (
+                "Main.vm",
+                "function Main.main 0\ncall Main.f 0\ncall Math.abs 1\nfunction Main.f 0\nfunction Main.g 0\ncall Main.f 0\nfunction Main.f 1\n",
+            ),
+        ];
+        let programs: Vec<_> = sources
+            .iter()
+            .map(|(name, source)| parse_all(Parser::from_source(name, source), &mut diagnostics))
+            .collect();
+        let commands = || programs.iter().flat_map(|(_, commands)| commands);
+
+        check_calls(commands(), Some("Sys.init"), None, &mut diagnostics);
+        let errors = diagnostics.errors();
+        assert_eq!(2, errors.len());
+        assert_eq!(
+            "error: function `Main.f` is already defined at Main.vm:4\n --> Main.vm:7:10\n  |\n7 | function Main.f 1\n  |          ^^^^^^\n",
+            errors[0].to_string()
+        );
+        assert!(matches!(
+            &errors[1],
+            TranslateError::UndefinedFunction(span) if span.text == "Math.abs" && (span.line, span.column) == (3, 6)
+        ));
+        assert!(diagnostics.warnings().is_empty());
+
+        // other files may define the callees
+        let mut diagnostics = Diagnostics::new(None);
+        check_calls(commands(), None, None, &mut diagnostics);
+        assert!(matches!(
+            diagnostics.errors(),
+            [TranslateError::DuplicateFunction { .. }]
+        ));
+
+        // calls in unreachable functions do not make their callees reachable
+        let mut diagnostics = Diagnostics::new(None);
+        check_calls(commands(), None, Some("Sys.init"), &mut diagnostics);
+        let unreachable: Vec<&str> = diagnostics
+            .warnings()
+            .iter()
+            .map(|w| w.span().unwrap().text.as_str())
+            .collect();
+        assert_eq!(vec!["Sys.halt", "Main.g"], unreachable);
+        assert_eq!(
+            "warning: function `Sys.halt` is never called from `Sys.init`\n --> Sys.vm:3:10\n  |\n3 | function Sys.halt 0\n  |          ^^^^^^^^\n",
+            diagnostics.warnings()[0].to_string()
+        );
+
+        // an undefined entry is never silently ignored
+        let mut diagnostics = Diagnostics::new(None);
+        check_calls(commands(), None, Some("Main.none"), &mut diagnostics);
+        assert_eq!(
+            "warning: entry `Main.none` not found, reachability not checked\n",
+            diagnostics.warnings()[0].to_string()
+        );
+        let mut diagnostics = Diagnostics::new(None);
+        check_calls(
+            commands(),
+            Some("Main.none"),
+            Some("Main.none"),
+            &mut diagnostics,
+        );
+        assert!(matches!(
+            diagnostics.errors().last(),
+            Some(TranslateError::UndefinedEntry(entry)) if entry == "Main.none"
+        ));
+        assert!(diagnostics.warnings().is_empty());
+    }
+
+    #[test]
+    fn stack_test() {
+        let source = "push constant 1\nfunction Main.f 1\npush argument 0\nif-goto ELSE\npush constant 1\npush constant 2\ngoto END\nlabel ELSE\npush constant 3\nlabel END\nadd\nreturn\nfunction Main.g 0\npush constant 1\ncall Main.f 2\npop local 0\nreturn\nlabel DEAD\nadd\n";
+        let mut diagnostics = Diagnostics::new(None);
+        let (_, commands) = parse_all(Parser::from_source("Main.vm", source), &mut diagnostics);
+        let depths = check_stack(&commands, &mut diagnostics);
+        assert_eq!(
+            vec![("Main.f".to_string(), 2), ("Main.g".to_string(), 1)],
+            depths
+        );
+
+        let errors = diagnostics.errors();
+        let lines: Vec<usize> = errors.iter().map(|e| e.span().unwrap().line).collect();
+        // unreachable code is not checked
+        assert_eq!(vec![10, 15, 17], lines);
+        assert!(matches!(
+            errors[0],
+            TranslateError::InconsistentStackDepth { depths: (1, 2), .. }
+                | TranslateError::InconsistentStackDepth { depths: (2, 1), .. }
+        ));
+        assert_eq!(
+            "error: stack underflow, `call Main.f 2` pops 2 values but the stack holds 1\n  --> Main.vm:15:1\n   |\n15 | call Main.f 2\n   | ^^^^^^^^^^^^^\n",
+            errors[1].to_string()
+        );
+        assert!(errors[2]
+            .to_string()
+            .starts_with("error: stack underflow, `return` pops 1 value but the stack holds 0\n"));
+    }
+
+    #[test]
+    fn sample_test() {
+        for path in ["BasicLoop.vm", "SimpleFunction.vm"] {
+            let mut diagnostics = Diagnostics::new(None);
+            let (_, commands) = parse_all(Parser::new(path).unwrap(), &mut diagnostics);
+            check_labels(&commands, &mut diagnostics);
+            assert!(diagnostics.is_empty());
+            assert!(diagnostics.warnings().is_empty());
+        }
+
+        let mut diagnostics = Diagnostics::new(None);
+        let programs: Vec<_> = ["FibonacciElement/Main.vm", "FibonacciElement/Sys.vm"]
+            .iter()
+            .map(|path| parse_all(Parser::new(path).unwrap(), &mut diagnostics))
+            .collect();
+        let commands = || programs.iter().flat_map(|(_, commands)| commands);
+        check_calls(
+            commands(),
+            Some("Sys.init"),
+            Some("Sys.init"),
+            &mut diagnostics,
+        );
+        let depths = check_stack(commands(), &mut diagnostics);
+        assert_eq!(
+            vec![
+                ("Main.fibonacci".to_string(), 3),
+                ("Sys.init".to_string(), 1)
+            ],
+            depths
+        );
+        assert!(diagnostics.is_empty());
+        assert!(diagnostics.warnings().is_empty());
+    }
+}
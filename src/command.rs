@@ -0,0 +1,123 @@
+use std::fmt;
+
+use strum_macros::{Display, EnumString};
+
+use crate::{error::SourceSpan, parser::CommandType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumString, Display)]
+pub enum ArithOp {
+    #[strum(serialize = "add")]
+    Add,
+    #[strum(serialize = "sub")]
+    Sub,
+    #[strum(serialize = "neg")]
+    Neg,
+    #[strum(serialize = "eq")]
+    Eq,
+    #[strum(serialize = "gt")]
+    Gt,
+    #[strum(serialize = "lt")]
+    Lt,
+    #[strum(serialize = "and")]
+    And,
+    #[strum(serialize = "or")]
+    Or,
+    #[strum(serialize = "not")]
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumString, Display)]
+pub enum Segment {
+    #[strum(serialize = "constant")]
+    Constant,
+    #[strum(serialize = "static")]
+    Static,
+    #[strum(serialize = "local")]
+    Local,
+    #[strum(serialize = "argument")]
+    Arg,
+    #[strum(serialize = "that")]
+    That,
+    #[strum(serialize = "this")]
+    This,
+    #[strum(serialize = "temp")]
+    Temp,
+    #[strum(serialize = "pointer")]
+    Pointer,
+}
+
+impl Segment {
+    pub fn max_index(&self) -> u16 {
+        match self {
+            Segment::Temp => 7,
+            Segment::Pointer => 1,
+            _ => 32767,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmCommand {
+    Arithmetic(ArithOp),
+    Push { segment: Segment, index: u16 },
+    Pop { segment: Segment, index: u16 },
+    Label(String),
+    Goto(String),
+    IfGoto(String),
+    Function { name: String, n_vars: u16 },
+    Call { name: String, n_args: u16 },
+    Return,
+}
+
+impl VmCommand {
+    pub fn command_type(&self) -> CommandType {
+        match self {
+            VmCommand::Arithmetic(_) => CommandType::C_ARITHMETIC,
+            VmCommand::Push { .. } => CommandType::C_PUSH,
+            VmCommand::Pop { .. } => CommandType::C_POP,
+            VmCommand::Label(_) => CommandType::C_LABEL,
+            VmCommand::Goto(_) => CommandType::C_GOTO,
+            VmCommand::IfGoto(_) => CommandType::C_IF,
+            VmCommand::Function { .. } => CommandType::C_FUNCTION,
+            VmCommand::Call { .. } => CommandType::C_CALL,
+            VmCommand::Return => CommandType::C_RETURN,
+        }
+    }
+}
+
+/// VM text of the command, e.g. `push constant 7`
+impl fmt::Display for VmCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmCommand::Arithmetic(op) => write!(f, "{}", op),
+            VmCommand::Push { segment, index } => write!(f, "push {} {}", segment, index),
+            VmCommand::Pop { segment, index } => write!(f, "pop {} {}", segment, index),
+            VmCommand::Label(label) => write!(f, "label {}", label),
+            VmCommand::Goto(label) => write!(f, "goto {}", label),
+            VmCommand::IfGoto(label) => write!(f, "if-goto {}", label),
+            VmCommand::Function { name, n_vars } => write!(f, "function {} {}", name, n_vars),
+            VmCommand::Call { name, n_args } => write!(f, "call {} {}", name, n_args),
+            VmCommand::Return => write!(f, "return"),
+        }
+    }
+}
+
+/// A value together with the place it was parsed from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: SourceSpan,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::Parser;
+
+    #[test]
+    fn display_test() {
+        let source = "add\npush constant 7\npop argument 2\nlabel LOOP\ngoto LOOP\nif-goto END\nfunction Main.main 3\ncall Math.multiply 2\nreturn";
+        let parser = Parser::from_source("Main.vm", source);
+        let printed: Vec<String> = parser.map(|c| c.unwrap().node.to_string()).collect();
+        assert_eq!(source.lines().collect::<Vec<_>>(), printed);
+    }
+}
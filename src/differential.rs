@@ -0,0 +1,423 @@
+//! Differential tests: random VM programs are run by the interpreter and, translated and assembled,
+//! by the CPU emulator. Both must end with the same stack and memory.
+//!
+//! Programs are generated from a sequence of choices. A failing sequence is shrunk by deleting and
+//! lowering choices, so the reported program is a minimal reproducer.
+
+use crate::{emulator::Emulator, interpreter::Interpreter, translate, Options};
+
+const CASES: u64 = 500;
+const MAX_STEPS: usize = 200_000;
+/// every optimization level is checked against the interpreter
+const MAX_OPT_LEVEL: u8 = 3;
+
+/// base of the memory pointed by THIS and THAT
+const HEAP_BASE: u16 = 3000;
+
+/// xorshift64*, good enough to pick choices
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+/// Choices consumed by the generator, drawn randomly or replayed while shrinking.
+struct Choices {
+    values: Vec<u64>,
+    pos: usize,
+    rng: Option<Rng>,
+}
+
+impl Choices {
+    fn random(seed: u64) -> Choices {
+        Choices {
+            values: vec![],
+            pos: 0,
+            rng: Some(Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)),
+        }
+    }
+
+    fn replay(values: Vec<u64>) -> Choices {
+        Choices {
+            values,
+            pos: 0,
+            rng: None,
+        }
+    }
+
+    /// a value in 0..n, 0 once replayed choices are exhausted
+    fn draw(&mut self, n: u64) -> u64 {
+        if self.pos == self.values.len() {
+            match &mut self.rng {
+                Some(rng) => {
+                    let value = rng.next();
+                    self.values.push(value);
+                }
+                None => return 0,
+            }
+        }
+        self.pos += 1;
+        self.values[self.pos - 1] % n
+    }
+}
+
+struct Function {
+    name: String,
+    n_args: u64,
+    n_vars: u64,
+}
+
+struct Generator<'a> {
+    choices: &'a mut Choices,
+    functions: Vec<Function>,
+    labels: usize,
+    /// commands left before blocks stop growing
+    budget: usize,
+}
+
+impl Generator<'_> {
+    fn constant(&mut self) -> u64 {
+        const INTERESTING: [u64; 6] = [1, 2, 32767, 32766, 16384, 12345];
+        match self.choices.draw(3) {
+            0 => self.choices.draw(10),
+            1 => INTERESTING[self.choices.draw(INTERESTING.len() as u64) as usize],
+            _ => self.choices.draw(32768),
+        }
+    }
+
+    /// a segment and index readable and writable in `function`
+    fn segment(&mut self, function: usize) -> (&'static str, u64) {
+        let f = &self.functions[function];
+        let (n_args, n_vars) = (f.n_args, f.n_vars);
+        match self.choices.draw(6) {
+            1 => ("static", self.choices.draw(4)),
+            2 => ("this", self.choices.draw(5)),
+            3 => ("that", self.choices.draw(5)),
+            4 if n_vars > 0 => ("local", self.choices.draw(n_vars)),
+            5 if n_args > 0 => ("argument", self.choices.draw(n_args)),
+            _ => ("temp", self.choices.draw(8)),
+        }
+    }
+
+    /// Generates commands which never pop below `floor`, starting with `depth` values on the stack.
+    ///
+    /// Returns the depth at the end of the block.
+    fn block(
+        &mut self,
+        out: &mut Vec<String>,
+        function: usize,
+        floor: u64,
+        mut depth: u64,
+        nesting: usize,
+    ) -> u64 {
+        while self.budget > 0 {
+            self.budget -= 1;
+            let available = depth - floor;
+            match self.choices.draw(8) {
+                0 => break,
+                2 => {
+                    let (segment, index) = self.segment(function);
+                    out.push(format!("push {} {}", segment, index));
+                    depth += 1;
+                }
+                3 if available >= 1 => {
+                    let (segment, index) = self.segment(function);
+                    out.push(format!("pop {} {}", segment, index));
+                    depth -= 1;
+                }
+                4 if available >= 1 => {
+                    let op = ["neg", "not"][self.choices.draw(2) as usize];
+                    out.push(op.to_string());
+                }
+                5 if available >= 2 => {
+                    let ops = ["add", "sub", "and", "or", "eq", "gt", "lt"];
+                    out.push(ops[self.choices.draw(ops.len() as u64) as usize].to_string());
+                    depth -= 1;
+                }
+                6 if available >= 1 && nesting < 2 => {
+                    // forward jumps only, so every program ends
+                    let label = format!("L{}", self.labels);
+                    self.labels += 1;
+                    out.push(format!("if-goto {}", label));
+                    depth -= 1;
+                    let end = self.block(out, function, depth, depth, nesting + 1);
+                    for _ in depth..end {
+                        out.push("pop temp 7".to_string());
+                    }
+                    out.push(format!("label {}", label));
+                }
+                7 if function + 1 < self.functions.len() => {
+                    // callees are defined later, so there is no recursion
+                    let callee = function
+                        + 1
+                        + self
+                            .choices
+                            .draw((self.functions.len() - function - 1) as u64)
+                            as usize;
+                    let n_args = self.functions[callee].n_args;
+                    if n_args <= available {
+                        out.push(format!("call {} {}", self.functions[callee].name, n_args));
+                        depth = depth - n_args + 1;
+                    }
+                }
+                _ => {
+                    out.push(format!("push constant {}", self.constant()));
+                    depth += 1;
+                }
+            }
+        }
+        depth
+    }
+
+    /// Sys.vm with Sys.init calling into Main.vm helpers.
+    fn program(&mut self) -> Vec<(String, String)> {
+        self.functions.push(Function {
+            name: "Sys.init".to_string(),
+            n_args: 0,
+            n_vars: 0,
+        });
+        for i in 0..self.choices.draw(4) {
+            let n_args = self.choices.draw(3);
+            let n_vars = self.choices.draw(3);
+            self.functions.push(Function {
+                name: format!("Main.f{}", i),
+                n_args,
+                n_vars,
+            });
+        }
+
+        let mut sys = vec![
+            "function Sys.init 0".to_string(),
+            format!("push constant {}", HEAP_BASE),
+            "pop pointer 0".to_string(),
+            format!("push constant {}", HEAP_BASE + 50),
+            "pop pointer 1".to_string(),
+        ];
+        self.block(&mut sys, 0, 0, 0, 0);
+        sys.push("label END".to_string());
+        sys.push("goto END".to_string());
+
+        let mut main = vec![];
+        for function in 1..self.functions.len() {
+            let f = &self.functions[function];
+            main.push(format!("function {} {}", f.name, f.n_vars));
+            if self.choices.draw(2) == 0 {
+                // callees get their own this/that
+                main.push(format!(
+                    "push constant {}",
+                    HEAP_BASE + 100 * function as u16
+                ));
+                main.push("pop pointer 0".to_string());
+            }
+            if self.block(&mut main, function, 0, 0, 0) == 0 {
+                main.push("push constant 0".to_string());
+            }
+            main.push("return".to_string());
+        }
+        vec![
+            ("Sys.vm".to_string(), sys.join("\n")),
+            ("Main.vm".to_string(), main.join("\n")),
+        ]
+    }
+}
+
+fn generate(choices: &mut Choices) -> Vec<(String, String)> {
+    Generator {
+        choices,
+        functions: vec![],
+        labels: 0,
+        budget: 60,
+    }
+    .program()
+}
+
+/// Memory compared after both runs: pointers, temp, statics, the stack of Sys.init and the heap.
+fn snapshot(ram: &[u16]) -> Vec<(usize, i16)> {
+    let sp = ram[0] as usize;
+    (0..13)
+        .chain(16..32)
+        .chain(261..sp.max(261))
+        .chain(HEAP_BASE as usize..HEAP_BASE as usize + 500)
+        .map(|address| (address, ram[address] as i16))
+        .collect()
+}
+
+/// Describes how the two runs differ, None when they agree.
+fn check(files: &[(String, String)]) -> Option<String> {
+    let sources: Vec<(&str, &str)> = files
+        .iter()
+        .map(|(name, source)| (name.as_str(), source.as_str()))
+        .collect();
+    let options = Options {
+        entry: Some("Sys.init".to_string()),
+        ..Default::default()
+    };
+
+    let mut interpreter = match Interpreter::from_sources(&sources, &options) {
+        Ok(interpreter) => interpreter,
+        Err(diagnostics) => {
+            return Some(format!(
+                "interpreter rejected the program:\n{}",
+                diagnostics
+            ))
+        }
+    };
+    if let Err(e) = interpreter.run(MAX_STEPS) {
+        return Some(format!("interpreter failed: {}", e));
+    }
+    assert!(interpreter.is_halted(), "generated programs always end");
+
+    let expected = snapshot(interpreter.ram());
+    for optimize_size in [false, true] {
+        for opt_level in 0..=MAX_OPT_LEVEL {
+            let options = Options {
+                opt_level,
+                optimize_size,
+                ..options.clone()
+            };
+            if let Some(reason) = check_translation(&sources, &options, &expected) {
+                let size = if optimize_size {
+                    " --optimize-size"
+                } else {
+                    ""
+                };
+                return Some(format!("-O{}{}: {}", opt_level, size, reason));
+            }
+        }
+    }
+    None
+}
+
+fn check_translation(
+    sources: &[(&str, &str)],
+    options: &Options,
+    expected: &[(usize, i16)],
+) -> Option<String> {
+    let asm = match translate(sources, options) {
+        Ok(asm) => asm,
+        Err(diagnostics) => return Some(format!("translation failed:\n{}", diagnostics)),
+    };
+    let mut emulator = match Emulator::from_asm(&asm) {
+        Ok(emulator) => emulator,
+        Err(e) => return Some(format!("assembler failed: {}", e)),
+    };
+    emulator.run(MAX_STEPS * 100);
+    if !emulator.is_halted() {
+        return Some("emulator did not halt".to_string());
+    }
+
+    let actual = snapshot(emulator.ram());
+    if expected.len() != actual.len() {
+        return Some(format!(
+            "SP differs: interpreter {}, emulator {}",
+            expected[0].1, actual[0].1
+        ));
+    }
+    expected
+        .iter()
+        .zip(&actual)
+        .find(|(e, a)| e != a)
+        .map(|(e, a)| {
+            format!(
+                "RAM[{}] differs: interpreter {}, emulator {}",
+                e.0, e.1, a.1
+            )
+        })
+}
+
+fn fails(values: &[u64]) -> bool {
+    check(&generate(&mut Choices::replay(values.to_vec()))).is_some()
+}
+
+/// Deletes and lowers choices while `fails` holds.
+fn shrink(mut values: Vec<u64>, fails: impl Fn(&[u64]) -> bool) -> Vec<u64> {
+    loop {
+        let mut shrunk = false;
+        for size in [8, 4, 2, 1] {
+            let mut start = 0;
+            while start + size <= values.len() {
+                let mut candidate = values.clone();
+                candidate.drain(start..start + size);
+                if fails(&candidate) {
+                    values = candidate;
+                    shrunk = true;
+                } else {
+                    start += 1;
+                }
+            }
+        }
+        for i in 0..values.len() {
+            for lower in [0, values[i] / 2, values[i].saturating_sub(1)] {
+                if lower >= values[i] {
+                    continue;
+                }
+                let mut candidate = values.clone();
+                candidate[i] = lower;
+                if fails(&candidate) {
+                    values = candidate;
+                    shrunk = true;
+                    break;
+                }
+            }
+        }
+        if !shrunk {
+            return values;
+        }
+    }
+}
+
+#[test]
+fn interpreter_matches_emulator() {
+    for seed in 0..CASES {
+        let mut choices = Choices::random(seed);
+        let files = generate(&mut choices);
+        if check(&files).is_none() {
+            continue;
+        }
+
+        let values = shrink(choices.values, fails);
+        let files = generate(&mut Choices::replay(values));
+        let reason = check(&files).unwrap();
+        let program: Vec<String> = files
+            .iter()
+            .map(|(name, source)| format!("// {}\n{}", name, source))
+            .collect();
+        panic!(
+            "seed {}: {}\nminimal program:\n{}",
+            seed,
+            reason,
+            program.join("\n")
+        );
+    }
+}
+
+#[test]
+fn shrink_test() {
+    let has_gt = |values: &[u64]| {
+        generate(&mut Choices::replay(values.to_vec()))
+            .iter()
+            .any(|(_, source)| source.lines().any(|l| l == "gt"))
+    };
+    let mut choices = Choices::random(0);
+    for seed in 1.. {
+        generate(&mut choices);
+        if has_gt(&choices.values) {
+            break;
+        }
+        choices = Choices::random(seed);
+    }
+
+    let values = shrink(choices.values, has_gt);
+    let files = generate(&mut Choices::replay(values));
+    // two pushes and gt, besides the fixed setup and end of Sys.init
+    assert_eq!(
+        "push constant 0\npush constant 0\ngt\nlabel END\ngoto END",
+        files[0].1.lines().skip(5).collect::<Vec<_>>().join("\n")
+    );
+    assert_eq!("", files[1].1);
+}
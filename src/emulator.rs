@@ -0,0 +1,173 @@
+use crate::assembler::{assemble, AsmError, ROM_SIZE};
+
+/// Size of the Hack data memory (RAM, screen and keyboard).
+pub const RAM_SIZE: usize = 32768;
+
+/// Hack CPU with its instruction and data memory.
+pub struct Emulator {
+    rom: Vec<u16>,
+    program_len: usize,
+    ram: Vec<u16>,
+    a: u16,
+    d: u16,
+    pc: u16,
+}
+
+impl Emulator {
+    pub fn new(program: &[u16]) -> Emulator {
+        let mut rom = program.to_vec();
+        rom.resize(ROM_SIZE, 0);
+        Emulator {
+            rom,
+            program_len: program.len(),
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+        }
+    }
+
+    /// Assembles `asm` and loads it into ROM.
+    pub fn from_asm(asm: &str) -> Result<Emulator, AsmError> {
+        Ok(Emulator::new(&assemble(asm)?))
+    }
+
+    pub fn ram(&self) -> &[u16] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u16] {
+        &mut self.ram
+    }
+
+    pub fn a(&self) -> u16 {
+        self.a
+    }
+
+    pub fn d(&self) -> u16 {
+        self.d
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// Resets the program counter, keeping memory as it is.
+    pub fn reset(&mut self) {
+        self.pc = 0;
+    }
+
+    /// Executes one instruction.
+    pub fn step(&mut self) {
+        let instruction = self.rom[self.pc as usize];
+        if instruction & 0x8000 == 0 {
+            self.a = instruction;
+            self.pc = self.pc.wrapping_add(1) % ROM_SIZE as u16;
+            return;
+        }
+
+        let x = self.d;
+        let y = if instruction & 0x1000 != 0 {
+            self.ram[self.a as usize % RAM_SIZE]
+        } else {
+            self.a
+        };
+        let out = alu(x, y, (instruction >> 6) & 0b111111);
+
+        // M is addressed by A before this instruction updates it
+        if instruction & 0b001000 != 0 {
+            self.ram[self.a as usize % RAM_SIZE] = out;
+        }
+        let jump_to = self.a;
+        if instruction & 0b100000 != 0 {
+            self.a = out;
+        }
+        if instruction & 0b010000 != 0 {
+            self.d = out;
+        }
+
+        let value = out as i16;
+        let jump = match instruction & 0b111 {
+            0b000 => false,
+            0b001 => value > 0,
+            0b010 => value == 0,
+            0b011 => value >= 0,
+            0b100 => value < 0,
+            0b101 => value != 0,
+            0b110 => value <= 0,
+            _ => true,
+        };
+        self.pc = if jump {
+            jump_to % ROM_SIZE as u16
+        } else {
+            self.pc.wrapping_add(1) % ROM_SIZE as u16
+        };
+    }
+
+    /// True when the program sits in a tight `(END) @END 0;JMP` loop, or ran past its code.
+    pub fn is_halted(&self) -> bool {
+        let pc = self.pc as usize;
+        let curr = self.rom[pc];
+        if pc > 0 && curr == 0b1110101010000111 && self.rom[pc - 1] == (pc - 1) as u16 {
+            return true;
+        }
+        pc >= self.program_len
+    }
+
+    /// Executes until the program halts or `max_steps` instructions are executed.
+    ///
+    /// Returns the number of executed instructions.
+    pub fn run(&mut self, max_steps: usize) -> usize {
+        for steps in 0..max_steps {
+            if self.is_halted() {
+                return steps;
+            }
+            self.step();
+        }
+        max_steps
+    }
+}
+
+/// Hack ALU, `control` is zx nx zy ny f no.
+fn alu(x: u16, y: u16, control: u16) -> u16 {
+    let bit = |n: u16| control & (1 << (5 - n)) != 0;
+    let x = if bit(0) { 0 } else { x };
+    let x = if bit(1) { !x } else { x };
+    let y = if bit(2) { 0 } else { y };
+    let y = if bit(3) { !y } else { y };
+    let out = if bit(4) { x.wrapping_add(y) } else { x & y };
+    if bit(5) {
+        !out
+    } else {
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Emulator;
+
+    #[test]
+    fn alu_test() {
+        // Mult.asm of project 4: R2 = R0 * R1
+        let mut emulator = Emulator::from_asm(
+            "@R2\nM=0\n(LOOP)\n@R0\nD=M\n@END\nD;JLE\n@R1\nD=M\n@R2\nM=D+M\n@R0\nM=M-1\n@LOOP\n0;JMP\n(END)\n@END\n0;JMP",
+        )
+        .unwrap();
+        emulator.ram_mut()[0] = 6;
+        emulator.ram_mut()[1] = 7;
+        emulator.run(1000);
+        assert!(emulator.is_halted());
+        assert_eq!(42, emulator.ram()[2]);
+
+        // overflow wraps around
+        let mut emulator =
+            Emulator::from_asm("@32767\nD=A\nD=D+1\n@R0\nM=D\nM=!M\nD=-1\nAM=D-1").unwrap();
+        emulator.run(6);
+        assert_eq!(0x7fff, emulator.ram()[0]);
+        // AM=D-1 writes M at the address A held before the instruction
+        emulator.run(100);
+        assert_eq!(0xfffe, emulator.a());
+        assert_eq!(0xfffe, emulator.ram()[0]);
+    }
+}
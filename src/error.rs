@@ -0,0 +1,336 @@
+use std::fmt;
+
+use crate::assembler::AsmError;
+
+/// Position of an offending piece of text in a .vm file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceSpan {
+    pub path: String,
+    /// 1-origin line number
+    pub line: usize,
+    /// 1-origin column of `text` in `source_line`
+    pub column: usize,
+    /// offending text
+    pub text: String,
+    /// whole line as it appears in the file, used to quote it in diagnostics
+    pub source_line: String,
+}
+
+#[derive(Debug)]
+pub enum TranslateError {
+    /// input file cannot be opened or read
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    /// output cannot be written
+    Write(std::io::Error),
+    /// no .vm file is given
+    NoInput(String),
+    UnknownCommand(SourceSpan),
+    /// command has less arguments than expected, `expected` describes them
+    MissingArgument {
+        span: SourceSpan,
+        expected: &'static str,
+    },
+    UnexpectedArgument(SourceSpan),
+    InvalidIndex(SourceSpan),
+    IndexOutOfRange {
+        span: SourceSpan,
+        max: u16,
+    },
+    UnknownSegment(SourceSpan),
+    /// label or function name with characters outside the VM specification
+    InvalidName(SourceSpan),
+    PopConstant(SourceSpan),
+    /// goto or if-goto to a label not defined in its function
+    UndefinedLabel(SourceSpan),
+    /// label defined again in the same function
+    DuplicateLabel {
+        span: SourceSpan,
+        /// `path:line` of the first definition
+        first: String,
+    },
+    /// call of a function defined in no input file
+    UndefinedFunction(SourceSpan),
+    /// entry function of the program defined in no input file
+    UndefinedEntry(String),
+    /// function defined again, in the same or another file
+    DuplicateFunction {
+        span: SourceSpan,
+        /// `path:line` of the first definition
+        first: String,
+    },
+    /// command pops more values than the working stack of its function holds
+    StackUnderflow {
+        span: SourceSpan,
+        needed: usize,
+        depth: usize,
+    },
+    /// command reached with different working stack depths along different paths
+    InconsistentStackDepth {
+        span: SourceSpan,
+        depths: (usize, usize),
+    },
+    /// generated assembly is rejected by the assembler
+    Assemble(AsmError),
+}
+
+impl TranslateError {
+    pub fn span(&self) -> Option<&SourceSpan> {
+        match self {
+            TranslateError::Io { .. }
+            | TranslateError::Write(_)
+            | TranslateError::NoInput(_)
+            | TranslateError::UndefinedEntry(_)
+            | TranslateError::Assemble(_) => None,
+            TranslateError::UnknownCommand(span)
+            | TranslateError::UnexpectedArgument(span)
+            | TranslateError::InvalidIndex(span)
+            | TranslateError::UnknownSegment(span)
+            | TranslateError::InvalidName(span)
+            | TranslateError::PopConstant(span)
+            | TranslateError::UndefinedLabel(span)
+            | TranslateError::UndefinedFunction(span) => Some(span),
+            TranslateError::MissingArgument { span, .. }
+            | TranslateError::DuplicateLabel { span, .. }
+            | TranslateError::DuplicateFunction { span, .. }
+            | TranslateError::StackUnderflow { span, .. }
+            | TranslateError::InconsistentStackDepth { span, .. }
+            | TranslateError::IndexOutOfRange { span, .. } => Some(span),
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            TranslateError::Io { path, source } => format!("cannot read `{}`: {}", path, source),
+            TranslateError::Write(e) => format!("cannot write output: {}", e),
+            TranslateError::NoInput(path) => format!("no .vm file found in `{}`", path),
+            TranslateError::UnknownCommand(span) => format!("unknown command `{}`", span.text),
+            TranslateError::MissingArgument { span, expected } => {
+                format!("`{}` expects {}", span.text, expected)
+            }
+            TranslateError::UnexpectedArgument(span) => {
+                format!("unexpected argument `{}`", span.text)
+            }
+            TranslateError::InvalidIndex(span) => format!("invalid index `{}`", span.text),
+            TranslateError::IndexOutOfRange { span, max } => {
+                format!("index `{}` is out of range (0..={})", span.text, max)
+            }
+            TranslateError::UnknownSegment(span) => format!("unknown segment `{}`", span.text),
+            TranslateError::InvalidName(span) => format!(
+                "invalid name `{}`, expected letters, digits, `_`, `.` or `:` not beginning with a digit",
+                span.text
+            ),
+            TranslateError::PopConstant(_) => "cannot pop to the constant segment".to_string(),
+            TranslateError::UndefinedLabel(span) => format!("undefined label `{}`", span.text),
+            TranslateError::DuplicateLabel { span, first } => format!(
+                "label `{}` is already defined at {}",
+                span.text, first
+            ),
+            TranslateError::UndefinedFunction(span) => {
+                format!("undefined function `{}`", span.text)
+            }
+            TranslateError::UndefinedEntry(name) => {
+                format!("entry function `{}` is not defined", name)
+            }
+            TranslateError::DuplicateFunction { span, first } => format!(
+                "function `{}` is already defined at {}",
+                span.text, first
+            ),
+            TranslateError::StackUnderflow {
+                span,
+                needed,
+                depth,
+            } => format!(
+                "stack underflow, `{}` pops {} value{} but the stack holds {}",
+                span.text,
+                needed,
+                if *needed == 1 { "" } else { "s" },
+                depth
+            ),
+            TranslateError::InconsistentStackDepth { span, depths } => format!(
+                "`{}` is reached with a stack depth of {} on one path and {} on another",
+                span.text, depths.0, depths.1
+            ),
+            TranslateError::Assemble(e) => format!("cannot assemble the output: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for TranslateError {
+    /// rustc-style message:
+    ///
+    /// ```text
+    /// error: unknown command `pussh`
+    ///  --> StackTest.vm:8:1
+    ///   |
+    /// 8 | pussh constant 17
+    ///   | ^^^^^
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "error: {}", self.message())?;
+        match self.span() {
+            Some(span) => write_snippet(f, span),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Location of `span` with its source line, the text underlined.
+fn write_snippet(f: &mut fmt::Formatter<'_>, span: &SourceSpan) -> fmt::Result {
+    if span.line == 0 {
+        return writeln!(f, " --> {}", span.path);
+    }
+
+    let line_no = span.line.to_string();
+    let pad = " ".repeat(line_no.len());
+    writeln!(f, "{}--> {}:{}:{}", pad, span.path, span.line, span.column)?;
+    writeln!(f, "{} |", pad)?;
+    // columns are counted per byte, so a tab must occupy a single cell
+    writeln!(f, "{} | {}", line_no, span.source_line.replace('\t', " "))?;
+    writeln!(
+        f,
+        "{} | {}{}",
+        pad,
+        " ".repeat(span.column.saturating_sub(1)),
+        "^".repeat(span.text.chars().count().max(1))
+    )
+}
+
+impl std::error::Error for TranslateError {}
+
+/// Suspicious VM code, which is still translated.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    /// label no goto or if-goto of its function jumps to
+    UnusedLabel(SourceSpan),
+    /// function never called from the entry function, directly or not
+    UnreachableFunction { span: SourceSpan, entry: String },
+    /// reachability is asked for, but its entry function is defined in no input file
+    EntryNotFound(String),
+}
+
+impl Warning {
+    pub fn span(&self) -> Option<&SourceSpan> {
+        match self {
+            Warning::UnusedLabel(span) | Warning::UnreachableFunction { span, .. } => Some(span),
+            Warning::EntryNotFound(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::UnusedLabel(span) => writeln!(f, "warning: unused label `{}`", span.text)?,
+            Warning::UnreachableFunction { span, entry } => writeln!(
+                f,
+                "warning: function `{}` is never called from `{}`",
+                span.text, entry
+            )?,
+            Warning::EntryNotFound(entry) => writeln!(
+                f,
+                "warning: entry `{}` not found, reachability not checked",
+                entry
+            )?,
+        }
+        match self.span() {
+            Some(span) => write_snippet(f, span),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Errors collected across all input files of a run.
+#[derive(Debug)]
+pub struct Diagnostics {
+    errors: Vec<TranslateError>,
+    warnings: Vec<Warning>,
+    /// stop collecting when this many errors are reported, unlimited if None
+    max_errors: Option<usize>,
+    /// true once an error is dropped because `max_errors` was reached
+    truncated: bool,
+}
+
+impl Diagnostics {
+    /// At least one error is kept even with a `max_errors` of 0, so that a failing run is never
+    /// taken for a success.
+    pub fn new(max_errors: Option<usize>) -> Diagnostics {
+        Diagnostics {
+            errors: vec![],
+            warnings: vec![],
+            max_errors: max_errors.map(|max| max.max(1)),
+            truncated: false,
+        }
+    }
+
+    pub fn report(&mut self, e: TranslateError) {
+        if self.is_full() {
+            self.truncated = true;
+        } else {
+            self.errors.push(e);
+        }
+    }
+
+    /// Warnings do not count towards `max_errors`.
+    pub fn warn(&mut self, w: Warning) {
+        self.warnings.push(w);
+    }
+
+    /// true if no more errors are accepted and translation should stop
+    pub fn is_full(&self) -> bool {
+        match self.max_errors {
+            Some(max) => self.errors.len() >= max,
+            None => false,
+        }
+    }
+
+    /// true if no errors are reported, warnings aside
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn errors(&self) -> &[TranslateError] {
+        &self.errors
+    }
+
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for w in self.warnings() {
+            writeln!(f, "{}", w)?;
+        }
+        for e in self.errors() {
+            writeln!(f, "{}", e)?;
+        }
+        if self.truncated {
+            writeln!(
+                f,
+                "error: too many errors, stopped after {}",
+                self.errors.len()
+            )?;
+        }
+        match self.errors.len() {
+            0 => Ok(()),
+            1 => writeln!(f, "error: aborting due to 1 previous error"),
+            n => writeln!(f, "error: aborting due to {} previous errors", n),
+        }
+    }
+}
+
+impl From<std::io::Error> for TranslateError {
+    fn from(e: std::io::Error) -> Self {
+        TranslateError::Write(e)
+    }
+}
+
+impl From<AsmError> for TranslateError {
+    fn from(e: AsmError) -> Self {
+        TranslateError::Assemble(e)
+    }
+}
@@ -0,0 +1,263 @@
+//! VM-level constant folding and operand specialization (`-O2`).
+//!
+//! Only adjacent commands are combined. Labels are commands themselves, so a jump target never
+//! ends up inside a combined command.
+
+use crate::{
+    command::{ArithOp, Segment, Spanned, VmCommand},
+    error::SourceSpan,
+};
+
+/// Value read by a [`Folded::Move`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Constant(u16),
+    Memory(Segment, u16),
+}
+
+/// A VM command, or several adjacent ones replaced by a cheaper equivalent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Folded {
+    Command(Spanned<VmCommand>),
+    /// push of any 16-bit value, e.g. `push constant 5; neg` is `Push(-5)`
+    Push(u16),
+    /// `neg` or `not` applied in place to the stack top
+    Unary(ArithOp),
+    /// `push constant value` followed by `add`, `sub`, `and` or `or`, applied in place
+    WithConstant {
+        op: ArithOp,
+        value: u16,
+    },
+    /// `push from; pop segment index` without going through the stack
+    Move {
+        from: Operand,
+        segment: Segment,
+        index: u16,
+    },
+    /// `eq`, `gt` or `lt`, optionally followed by `not`, followed by `if-goto label`
+    CompareJump {
+        op: ArithOp,
+        negated: bool,
+        label: String,
+    },
+}
+
+fn eval(op: ArithOp, x: u16, y: u16) -> u16 {
+    let bool_value = |b: bool| if b { 0xffff } else { 0 };
+    match op {
+        ArithOp::Add => x.wrapping_add(y),
+        ArithOp::Sub => x.wrapping_sub(y),
+        ArithOp::Neg => y.wrapping_neg(),
+        ArithOp::Eq => bool_value(x == y),
+        ArithOp::Gt => bool_value(x as i16 > y as i16),
+        ArithOp::Lt => bool_value((x as i16) < y as i16),
+        ArithOp::And => x & y,
+        ArithOp::Or => x | y,
+        ArithOp::Not => !y,
+    }
+}
+
+/// Folds the commands of one file. Combined commands get the span of the first one.
+pub fn fold(commands: Vec<Spanned<VmCommand>>) -> Vec<Spanned<Folded>> {
+    let mut out: Vec<Folded> = Vec::with_capacity(commands.len());
+    // span of the first command of each item in `out`
+    let mut spans: Vec<SourceSpan> = Vec::with_capacity(commands.len());
+    for command in commands {
+        let span = command.span.clone();
+        match &command.node {
+            VmCommand::Push {
+                segment: Segment::Constant,
+                index,
+            } => out.push(Folded::Push(*index)),
+            VmCommand::Arithmetic(op @ (ArithOp::Neg | ArithOp::Not)) => match out.last_mut() {
+                Some(Folded::Push(value)) => *value = eval(*op, 0, *value),
+                _ => out.push(Folded::Unary(*op)),
+            },
+            VmCommand::Arithmetic(op) => match &out[out.len().saturating_sub(2)..] {
+                [Folded::Push(x), Folded::Push(y)] => {
+                    let value = eval(*op, *x, *y);
+                    out.truncate(out.len() - 2);
+                    out.push(Folded::Push(value));
+                }
+                [.., Folded::Push(y)]
+                    if matches!(op, ArithOp::Add | ArithOp::Sub | ArithOp::And | ArithOp::Or) =>
+                {
+                    let value = *y;
+                    out.pop();
+                    out.push(Folded::WithConstant { op: *op, value });
+                }
+                _ => out.push(Folded::Command(command)),
+            },
+            // pop to constant is left to the code writer, which reports it
+            VmCommand::Pop { segment, index } if *segment != Segment::Constant => {
+                let from = match out.last() {
+                    Some(Folded::Push(value)) => Some(Operand::Constant(*value)),
+                    Some(Folded::Command(Spanned {
+                        node: VmCommand::Push { segment, index },
+                        ..
+                    })) => Some(Operand::Memory(*segment, *index)),
+                    _ => None,
+                };
+                match from {
+                    Some(from) => {
+                        out.pop();
+                        out.push(Folded::Move {
+                            from,
+                            segment: *segment,
+                            index: *index,
+                        });
+                    }
+                    None => out.push(Folded::Command(command)),
+                }
+            }
+            VmCommand::IfGoto(label) => {
+                let negated = matches!(out.last(), Some(Folded::Unary(ArithOp::Not)));
+                let start = out.len().checked_sub(1 + negated as usize);
+                let op = start.and_then(|i| match &out[i] {
+                    Folded::Command(Spanned {
+                        node: VmCommand::Arithmetic(op @ (ArithOp::Eq | ArithOp::Gt | ArithOp::Lt)),
+                        ..
+                    }) => Some(*op),
+                    _ => None,
+                });
+                match (start, op) {
+                    (Some(start), Some(op)) => {
+                        out.truncate(start);
+                        out.push(Folded::CompareJump {
+                            op,
+                            negated,
+                            label: label.clone(),
+                        });
+                    }
+                    _ => out.push(Folded::Command(command)),
+                }
+            }
+            _ => out.push(Folded::Command(command)),
+        }
+        // at most one item is added, and replaced items start where they did
+        spans.truncate(out.len());
+        if spans.len() < out.len() {
+            spans.push(span);
+        }
+    }
+    out.into_iter()
+        .zip(spans)
+        .map(|(node, span)| Spanned { node, span })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fold, Folded, Operand};
+    use crate::{
+        command::{ArithOp, Segment},
+        parser::Parser,
+    };
+
+    fn fold_source(source: &str) -> Vec<Folded> {
+        fold(
+            Parser::from_source("Main.vm", source)
+                .map(|c| c.unwrap())
+                .collect(),
+        )
+        .into_iter()
+        .map(|folded| folded.node)
+        .collect()
+    }
+
+    #[test]
+    fn constant_test() {
+        assert_eq!(
+            vec![Folded::Push(-5i16 as u16)],
+            fold_source("push constant 5\nneg")
+        );
+        assert_eq!(
+            vec![Folded::Push(0xffff)],
+            fold_source("push constant 0\nnot")
+        );
+        assert_eq!(
+            vec![Folded::Push(0)],
+            fold_source("push constant 7\npush constant 8\nadd\npush constant 15\neq\nnot")
+        );
+        // 32767 + 1 wraps around like the Hack ALU
+        assert_eq!(
+            vec![Folded::Push(0x8000)],
+            fold_source("push constant 32767\npush constant 1\nadd")
+        );
+    }
+
+    #[test]
+    fn specialize_test() {
+        let folded = fold_source("push local 0\npush constant 1\nadd\nneg\npush constant 3\nlt");
+        assert_eq!(
+            Folded::WithConstant {
+                op: ArithOp::Add,
+                value: 1
+            },
+            folded[1]
+        );
+        assert_eq!(Folded::Unary(ArithOp::Neg), folded[2]);
+        // comparisons keep the general template
+        assert!(matches!(folded[4], Folded::Command(_)));
+    }
+
+    #[test]
+    fn compare_jump_test() {
+        assert_eq!(
+            Folded::CompareJump {
+                op: ArithOp::Lt,
+                negated: true,
+                label: "WHILE_END".to_string()
+            },
+            fold_source("push local 0\npush argument 0\nlt\nnot\nif-goto WHILE_END")[2]
+        );
+        assert_eq!(
+            Folded::CompareJump {
+                op: ArithOp::Eq,
+                negated: false,
+                label: "L".to_string()
+            },
+            fold_source("push local 0\npush constant 3\neq\nif-goto L")[2]
+        );
+        // the comparison result is still needed after a label
+        assert!(matches!(
+            fold_source("push local 0\npush argument 0\ngt\nlabel L\nif-goto L")[4],
+            Folded::Command(_)
+        ));
+    }
+
+    #[test]
+    fn span_test() {
+        let folded = fold(
+            Parser::from_source("Main.vm", "push local 0\npush constant 1\nadd\npop local 0")
+                .map(|c| c.unwrap())
+                .collect(),
+        );
+        let lines: Vec<usize> = folded.iter().map(|f| f.span.line).collect();
+        assert_eq!(vec![1, 2, 4], lines);
+    }
+
+    #[test]
+    fn move_test() {
+        assert_eq!(
+            vec![
+                Folded::Move {
+                    from: Operand::Constant(7),
+                    segment: Segment::Local,
+                    index: 2
+                },
+                Folded::Move {
+                    from: Operand::Memory(Segment::Arg, 1),
+                    segment: Segment::Static,
+                    index: 0
+                }
+            ],
+            fold_source("push constant 7\npop local 2\npush argument 1\npop static 0")
+        );
+        // a label between them is a jump target
+        assert_eq!(
+            3,
+            fold_source("push argument 1\nlabel L\npop static 0").len()
+        );
+    }
+}
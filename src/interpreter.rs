@@ -0,0 +1,519 @@
+//! Reference interpreter executing VM commands directly, without translating them to assembly.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    command::{ArithOp, Segment, Spanned, VmCommand},
+    emulator::RAM_SIZE,
+    error::{Diagnostics, SourceSpan, TranslateError},
+    parser::{parse_all, Parser},
+    Options,
+};
+
+/// First RAM address allocated to static variables, same as the assembler's variables.
+const STATIC_BASE: u16 = 16;
+
+/// Stack base set by the bootstrap code.
+pub const STACK_BASE: u16 = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    UnknownLabel(SourceSpan),
+    UnknownFunction(SourceSpan),
+    PopConstant(SourceSpan),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let span = match self {
+            RuntimeError::UnknownLabel(span) => {
+                write!(f, "label `{}` is not defined", span.text)?;
+                span
+            }
+            RuntimeError::UnknownFunction(span) => {
+                write!(f, "function `{}` is not defined", span.text)?;
+                span
+            }
+            RuntimeError::PopConstant(span) => {
+                write!(f, "cannot pop to the constant segment")?;
+                span
+            }
+        };
+        write!(f, " at {}:{}", span.path, span.line)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// VM program loaded into a Hack RAM image.
+pub struct Interpreter {
+    commands: Vec<Spanned<VmCommand>>,
+    /// function enclosing each command, labels are scoped by it
+    scopes: Vec<String>,
+    /// RAM address of `static` operands of each command
+    statics: Vec<Option<u16>>,
+    labels: HashMap<(String, String), usize>,
+    functions: HashMap<String, usize>,
+    ram: Vec<u16>,
+    pc: usize,
+}
+
+impl Interpreter {
+    /// Loads VM sources held in memory, see [`crate::translate`].
+    pub fn from_sources(
+        sources: &[(&str, &str)],
+        options: &Options,
+    ) -> Result<Interpreter, Diagnostics> {
+        let mut diagnostics = Diagnostics::new(options.max_errors);
+        let mut programs = vec![];
+        for (name, source) in sources {
+            programs.push(parse_all(
+                Parser::from_source(name, source),
+                &mut diagnostics,
+            ));
+        }
+        Interpreter::link(programs, options, diagnostics)
+    }
+
+    /// Loads .vm files.
+    pub fn from_files(inputs: &[String], options: &Options) -> Result<Interpreter, Diagnostics> {
+        let mut diagnostics = Diagnostics::new(options.max_errors);
+        let mut programs = vec![];
+        for f in inputs {
+            match Parser::new(f) {
+                Ok(parser) => programs.push(parse_all(parser, &mut diagnostics)),
+                Err(e) => diagnostics.report(e),
+            }
+        }
+        Interpreter::link(programs, options, diagnostics)
+    }
+
+    fn link(
+        programs: Vec<(String, Vec<Spanned<VmCommand>>)>,
+        options: &Options,
+        mut diagnostics: Diagnostics,
+    ) -> Result<Interpreter, Diagnostics> {
+        let mut interpreter = Interpreter {
+            commands: vec![],
+            scopes: vec![],
+            statics: vec![],
+            labels: HashMap::new(),
+            functions: HashMap::new(),
+            ram: vec![0; RAM_SIZE],
+            pc: 0,
+        };
+        // statics are allocated in order of appearance, like the assembler allocates variables
+        let mut static_addresses: HashMap<(String, u16), u16> = HashMap::new();
+        let mut scope = String::new();
+        for (file, commands) in programs {
+            for command in commands {
+                let address = match &command.node {
+                    VmCommand::Push {
+                        segment: Segment::Static,
+                        index,
+                    }
+                    | VmCommand::Pop {
+                        segment: Segment::Static,
+                        index,
+                    } => {
+                        let next = STATIC_BASE + static_addresses.len() as u16;
+                        Some(
+                            *static_addresses
+                                .entry((file.clone(), *index))
+                                .or_insert(next),
+                        )
+                    }
+                    _ => None,
+                };
+                match &command.node {
+                    VmCommand::Function { name, .. } => {
+                        scope = name.clone();
+                        interpreter
+                            .functions
+                            .insert(name.clone(), interpreter.commands.len());
+                    }
+                    VmCommand::Label(label) => {
+                        interpreter
+                            .labels
+                            .insert((scope.clone(), label.clone()), interpreter.commands.len());
+                    }
+                    _ => {}
+                }
+                interpreter.statics.push(address);
+                interpreter.scopes.push(scope.clone());
+                interpreter.commands.push(command);
+            }
+        }
+
+        if let Some(entry) = &options.entry {
+            interpreter.ram[0] = STACK_BASE;
+            // returning from the entry function ends the program
+            let end = interpreter.commands.len();
+            match interpreter.functions.get(entry) {
+                Some(&target) => interpreter.call(end, target, 0),
+                None => diagnostics.report(TranslateError::UndefinedEntry(entry.clone())),
+            }
+        } else if options.debug {
+            interpreter.ram[0] = STACK_BASE;
+        }
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        Ok(interpreter)
+    }
+
+    pub fn ram(&self) -> &[u16] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u16] {
+        &mut self.ram
+    }
+
+    /// Index of the next command to execute.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Values from the stack base up to SP, bottom first.
+    pub fn stack(&self, base: u16) -> &[u16] {
+        let sp = (self.ram[0] as usize).min(RAM_SIZE);
+        &self.ram[(base as usize).min(sp)..sp]
+    }
+
+    /// True when the program ran past its last command or sits in a loop of `goto` to itself.
+    pub fn is_halted(&self) -> bool {
+        let command = match self.commands.get(self.pc) {
+            Some(command) => command,
+            None => return true,
+        };
+        match &command.node {
+            VmCommand::Goto(label) => match self.label(label) {
+                Some(target) if target <= self.pc => self.commands[target..self.pc]
+                    .iter()
+                    .all(|c| matches!(c.node, VmCommand::Label(_))),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Executes until the program halts or `max_steps` commands are executed.
+    ///
+    /// Returns the number of executed commands.
+    pub fn run(&mut self, max_steps: usize) -> Result<usize, RuntimeError> {
+        for steps in 0..max_steps {
+            if self.is_halted() {
+                return Ok(steps);
+            }
+            self.step()?;
+        }
+        Ok(max_steps)
+    }
+
+    /// Executes one command, nothing once the program ran past its last command.
+    pub fn step(&mut self) -> Result<(), RuntimeError> {
+        let command = match self.commands.get(self.pc) {
+            Some(command) => command.clone(),
+            None => return Ok(()),
+        };
+        let span = || command.span.clone();
+        let mut next = self.pc + 1;
+        match &command.node {
+            VmCommand::Arithmetic(op) => self.arithmetic(*op),
+            VmCommand::Push { segment, index } => {
+                let value = match segment {
+                    Segment::Constant => *index,
+                    _ => self.ram[self.address(*segment, *index)],
+                };
+                self.push(value);
+            }
+            VmCommand::Pop {
+                segment: Segment::Constant,
+                ..
+            } => return Err(RuntimeError::PopConstant(span())),
+            VmCommand::Pop { segment, index } => {
+                let value = self.pop();
+                let address = self.address(*segment, *index);
+                self.ram[address] = value;
+            }
+            VmCommand::Label(_) => {}
+            VmCommand::Goto(label) => {
+                next = self
+                    .label(label)
+                    .ok_or_else(|| RuntimeError::UnknownLabel(span()))?;
+            }
+            VmCommand::IfGoto(label) => {
+                if self.pop() != 0 {
+                    next = self
+                        .label(label)
+                        .ok_or_else(|| RuntimeError::UnknownLabel(span()))?;
+                }
+            }
+            VmCommand::Function { n_vars, .. } => {
+                for _ in 0..*n_vars {
+                    self.push(0);
+                }
+            }
+            VmCommand::Call { name, n_args } => {
+                let target = *self.functions.get(name).ok_or_else(|| {
+                    let mut span = span();
+                    span.text = name.clone();
+                    RuntimeError::UnknownFunction(span)
+                })?;
+                let n_args = *n_args;
+                self.call(next, target, n_args);
+                return Ok(());
+            }
+            VmCommand::Return => {
+                let frame = self.ram[1];
+                let address = self.ram[frame.wrapping_sub(5) as usize % RAM_SIZE];
+                let value = self.pop();
+                let arg = self.ram[2];
+                self.ram[arg as usize % RAM_SIZE] = value;
+                self.ram[0] = arg.wrapping_add(1);
+                for (i, register) in [4, 3, 2, 1].iter().enumerate() {
+                    self.ram[*register] =
+                        self.ram[frame.wrapping_sub(i as u16 + 1) as usize % RAM_SIZE];
+                }
+                // an address past the last command, e.g. in a frame set up by a test script, ends the program
+                next = address as usize;
+            }
+        }
+        self.pc = next;
+        Ok(())
+    }
+
+    /// Pushes the frame of the caller and jumps to `target`.
+    fn call(&mut self, return_address: usize, target: usize, n_args: u16) {
+        self.push(return_address as u16);
+        for register in 1..=4 {
+            self.push(self.ram[register]);
+        }
+        self.ram[2] = self.ram[0].wrapping_sub(5 + n_args);
+        self.ram[1] = self.ram[0];
+        self.pc = target;
+    }
+
+    fn label(&self, label: &str) -> Option<usize> {
+        self.labels
+            .get(&(self.scopes[self.pc].clone(), label.to_string()))
+            .copied()
+    }
+
+    /// RAM address of a segment entry, mapped as in the code writer.
+    fn address(&self, segment: Segment, index: u16) -> usize {
+        let address = match segment {
+            Segment::Local => self.ram[1].wrapping_add(index),
+            Segment::Arg => self.ram[2].wrapping_add(index),
+            Segment::This => self.ram[3].wrapping_add(index),
+            Segment::That => self.ram[4].wrapping_add(index),
+            Segment::Temp => 5 + index,
+            Segment::Pointer => 3 + index,
+            Segment::Static => self.statics[self.pc].expect("allocated when linked"),
+            Segment::Constant => unreachable!("constant has no address"),
+        };
+        address as usize % RAM_SIZE
+    }
+
+    fn push(&mut self, value: u16) {
+        let sp = self.ram[0];
+        self.ram[sp as usize % RAM_SIZE] = value;
+        self.ram[0] = sp.wrapping_add(1);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let sp = self.ram[0].wrapping_sub(1);
+        self.ram[0] = sp;
+        self.ram[sp as usize % RAM_SIZE]
+    }
+
+    fn arithmetic(&mut self, op: ArithOp) {
+        let y = self.pop();
+        let value = match op {
+            ArithOp::Neg => y.wrapping_neg(),
+            ArithOp::Not => !y,
+            _ => {
+                let x = self.pop();
+                let bool_value = |b: bool| if b { 0xffff } else { 0 };
+                match op {
+                    ArithOp::Add => x.wrapping_add(y),
+                    ArithOp::Sub => x.wrapping_sub(y),
+                    ArithOp::And => x & y,
+                    ArithOp::Or => x | y,
+                    ArithOp::Eq => bool_value(x == y),
+                    ArithOp::Gt => bool_value(x as i16 > y as i16),
+                    ArithOp::Lt => bool_value((x as i16) < y as i16),
+                    ArithOp::Neg | ArithOp::Not => unreachable!(),
+                }
+            }
+        };
+        self.push(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Interpreter, RuntimeError};
+    use crate::Options;
+
+    fn read(path: &str) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    fn run(sources: &[(&str, &str)], options: &Options, init: &[(usize, i16)]) -> Interpreter {
+        let mut interpreter = Interpreter::from_sources(sources, options).unwrap();
+        for (address, value) in init {
+            interpreter.ram_mut()[*address] = *value as u16;
+        }
+        interpreter.run(1_000_000).unwrap();
+        assert!(interpreter.is_halted());
+        interpreter
+    }
+
+    fn assert_ram(interpreter: &Interpreter, expected: &[(usize, i16)]) {
+        for (address, value) in expected {
+            assert_eq!(
+                *value,
+                interpreter.ram()[*address] as i16,
+                "RAM[{}]",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn stack_test() {
+        let options = Options {
+            debug: true,
+            ..Default::default()
+        };
+        let interpreter = run(&[("StackTest.vm", &read("StackTest.vm"))], &options, &[]);
+        let expected: Vec<u16> = [-1, 0, 0, 0, -1, 0, -1, 0, 0, -91]
+            .iter()
+            .map(|v: &i16| *v as u16)
+            .collect();
+        assert_eq!(&expected[..], interpreter.stack(256));
+    }
+
+    #[test]
+    fn static_test() {
+        let options = Options {
+            debug: true,
+            ..Default::default()
+        };
+        let interpreter = run(&[("StaticTest.vm", &read("StaticTest.vm"))], &options, &[]);
+        assert_ram(&interpreter, &[(256, 1110)]);
+        // allocated from RAM 16 like the assembler does
+        assert_eq!(888, interpreter.ram()[16]);
+    }
+
+    #[test]
+    fn simple_function_test() {
+        let interpreter = run(
+            &[("SimpleFunction.vm", &read("SimpleFunction.vm"))],
+            &Options::default(),
+            &[
+                (0, 317),
+                (1, 317),
+                (2, 310),
+                (3, 3000),
+                (4, 4000),
+                (310, 1234),
+                (311, 37),
+                (312, 1000),
+                (313, 305),
+                (314, 300),
+                (315, 3010),
+                (316, 4010),
+            ],
+        );
+        assert_ram(
+            &interpreter,
+            &[
+                (0, 311),
+                (1, 305),
+                (2, 300),
+                (3, 3010),
+                (4, 4010),
+                (310, 1196),
+            ],
+        );
+    }
+
+    #[test]
+    fn fibonacci_element_test() {
+        let options = Options {
+            entry: Some("Sys.init".to_string()),
+            ..Default::default()
+        };
+        let interpreter = run(
+            &[
+                ("Main.vm", &read("FibonacciElement/Main.vm")),
+                ("Sys.vm", &read("FibonacciElement/Sys.vm")),
+            ],
+            &options,
+            &[],
+        );
+        assert_ram(&interpreter, &[(0, 262), (261, 3)]);
+    }
+
+    #[test]
+    fn label_scope_test() {
+        // the same label in two functions
+        let source = "function Main.f 0\nlabel END\npush constant 1\nreturn\nfunction Sys.init 0\ncall Main.f 0\ngoto END\npush constant 2\nlabel END\ngoto END\n";
+        let options = Options {
+            entry: Some("Sys.init".to_string()),
+            ..Default::default()
+        };
+        let interpreter = run(&[("Main.vm", source)], &options, &[]);
+        assert_eq!(&[1], interpreter.stack(261));
+    }
+
+    #[test]
+    fn error_test() {
+        let mut interpreter = Interpreter::from_sources(
+            &[("Main.vm", "push constant 1\ncall Math.multiply 1\n")],
+            &Options::default(),
+        )
+        .unwrap();
+        let e = interpreter.run(10).unwrap_err();
+        assert!(matches!(&e, RuntimeError::UnknownFunction(span) if span.line == 2));
+        assert_eq!(
+            "function `Math.multiply` is not defined at Main.vm:2",
+            e.to_string()
+        );
+
+        let diagnostics =
+            Interpreter::from_sources(&[("Main.vm", "push constant\n")], &Options::default())
+                .err()
+                .unwrap();
+        assert_eq!(1, diagnostics.errors().len());
+
+        let options = Options {
+            entry: Some("Nope.main".to_string()),
+            ..Default::default()
+        };
+        let diagnostics =
+            Interpreter::from_sources(&[("Main.vm", "function Main.main 0\n")], &options)
+                .err()
+                .unwrap();
+        assert!(diagnostics
+            .to_string()
+            .starts_with("error: entry function `Nope.main` is not defined\n"));
+    }
+
+    #[test]
+    fn halted_step_test() {
+        let options = Options {
+            debug: true,
+            ..Default::default()
+        };
+        let mut interpreter = run(&[("Main.vm", "push constant 1\n")], &options, &[]);
+        assert_eq!(1, interpreter.pc());
+        // stepping past the end does nothing
+        interpreter.step().unwrap();
+        assert_eq!(1, interpreter.pc());
+        assert_eq!(&[1], interpreter.stack(256));
+    }
+}
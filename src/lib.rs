@@ -0,0 +1,532 @@
+//! VM translator for the nand2tetris Hack platform (projects 7 and 8).
+//!
+//! ```
+//! let asm = vmtrans::translate(&[("Main.vm", "push constant 7\n")], &Default::default()).unwrap();
+//! assert!(asm.contains("@7"));
+//! ```
+
+use asm::Asm;
+use command::{Spanned, VmCommand};
+use parser::parse_all;
+
+pub mod asm;
+pub mod assembler;
+pub mod check;
+pub mod command;
+#[cfg(test)]
+mod differential;
+pub mod emulator;
+pub mod error;
+pub mod fold;
+pub mod interpreter;
+pub mod parser;
+pub mod peephole;
+pub mod sourcemap;
+mod template;
+pub mod tst;
+pub mod writer;
+
+pub use error::{Diagnostics, SourceSpan, TranslateError};
+pub use parser::Parser;
+pub use writer::CodeWriter;
+
+pub static DEFAULT_ENTRY: &str = "Sys.init";
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// emit the bootstrap code calling this function before everything else, the inputs are then
+    /// the whole program and calls of undefined functions, or of an undefined entry, are errors
+    pub entry: Option<String>,
+    /// only init Stack Pointer, for single file tests without bootstrap code
+    pub debug: bool,
+    /// stop after this many errors are reported, unlimited if None
+    pub max_errors: Option<usize>,
+    /// 0: templates as they are, 1: peephole optimization, 2: also VM-level constant folding,
+    /// 3: also caching of the stack top in D
+    pub opt_level: u8,
+    /// share one subroutine per comparison kind, one for call and one for return instead of
+    /// inlining them, smaller but slower code
+    pub optimize_size: bool,
+    /// comment each command in the output with its file, line and VM text
+    pub annotate: bool,
+    /// warn about functions never called from the entry function, [`DEFAULT_ENTRY`] if not given
+    pub warn_unreachable: bool,
+}
+
+/// Translates VM sources held in memory into Hack assembly.
+///
+/// `sources` are pairs of a file name (used for static variables and diagnostics) and its VM code.
+pub fn translate(sources: &[(&str, &str)], options: &Options) -> Result<String, Diagnostics> {
+    let asm = translate_asm(sources, options)?;
+    if options.annotate {
+        Ok(asm.to_string())
+    } else {
+        Ok(asm.without_origins().to_string())
+    }
+}
+
+/// [`translate`] before the assembly is printed, the origins of the commands are always kept.
+pub(crate) fn translate_asm(
+    sources: &[(&str, &str)],
+    options: &Options,
+) -> Result<Asm, Diagnostics> {
+    let mut diagnostics = Diagnostics::new(options.max_errors);
+    let programs = sources
+        .iter()
+        .map(|(name, source)| parse_all(Parser::from_source(name, source), &mut diagnostics))
+        .collect();
+    let mut output = CodeWriter::new();
+    write_program(programs, &mut output, options, &mut diagnostics);
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    Ok(optimize(output.finish(), options))
+}
+
+/// Applies the optimizations enabled by `options.opt_level` to translated assembly.
+///
+/// Comments are dropped, the origins of the instructions are kept.
+pub fn optimize(asm: Asm, options: &Options) -> Asm {
+    if options.opt_level == 0 {
+        return asm;
+    }
+    Asm::from_tagged(peephole::optimize_tagged(asm.tagged()))
+}
+
+/// Translates .vm files into `output`.
+///
+/// Errors in the VM code are collected into `diagnostics`, nothing is written when the code has
+/// errors. The output is not optimized, see [`optimize`].
+///
+/// Returns the maximum working stack depth of each function, see [`check::check_stack`].
+pub fn compile(
+    inputs: &[String],
+    output: &mut CodeWriter,
+    options: &Options,
+    diagnostics: &mut Diagnostics,
+) -> Vec<(String, usize)> {
+    let mut programs = vec![];
+    for f in inputs {
+        match Parser::new(f) {
+            Ok(parser) => programs.push(parse_all(parser, diagnostics)),
+            Err(e) => diagnostics.report(e),
+        }
+    }
+    write_program(programs, output, options, diagnostics)
+}
+
+fn write_prologue(output: &mut CodeWriter, options: &Options) {
+    output.setSizeMode(options.optimize_size);
+    output.setStackTopCache(options.opt_level >= 3);
+    if let Some(entry) = &options.entry {
+        output.writeInit(entry);
+    } else if options.debug {
+        // only for debugging to init Stack Pointer
+        output.debug();
+    }
+}
+
+/// Checks the parsed files as a whole, then writes them if no errors are found.
+///
+/// Returns the stack depths found by the checks.
+fn write_program(
+    programs: Vec<(String, Vec<Spanned<VmCommand>>)>,
+    output: &mut CodeWriter,
+    options: &Options,
+    diagnostics: &mut Diagnostics,
+) -> Vec<(String, usize)> {
+    let commands = || programs.iter().flat_map(|(_, commands)| commands);
+    check::check_labels(commands(), diagnostics);
+    let entry = options.entry.as_deref();
+    check::check_calls(
+        commands(),
+        entry,
+        options
+            .warn_unreachable
+            .then_some(entry.unwrap_or(DEFAULT_ENTRY)),
+        diagnostics,
+    );
+    let depths = check::check_stack(commands(), diagnostics);
+    if !diagnostics.is_empty() {
+        return depths;
+    }
+
+    write_prologue(output, options);
+    for (file, commands) in programs {
+        output.setFileName(&file);
+        if options.opt_level >= 2 {
+            // folding needs the following commands, so it works on the whole file
+            for folded in fold::fold(commands) {
+                if let Err(e) = output.writeFolded(&folded) {
+                    diagnostics.report(e);
+                }
+            }
+        } else {
+            for command in &commands {
+                if let Err(e) = output.writeCommand(command) {
+                    diagnostics.report(e);
+                }
+            }
+        }
+    }
+    depths
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compile, emulator::Emulator, error::Diagnostics, translate, writer::CodeWriter, Options,
+        TranslateError,
+    };
+
+    pub(crate) fn read(path: &str) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    /// Translates and runs VM code on the CPU emulator until it halts.
+    pub(crate) fn run_vm(
+        sources: &[(&str, &str)],
+        options: &Options,
+        init: &[(usize, i16)],
+    ) -> Emulator {
+        let asm = translate(sources, options).unwrap();
+        let mut emulator = Emulator::from_asm(&asm).unwrap();
+        for (address, value) in init {
+            emulator.ram_mut()[*address] = *value as u16;
+        }
+        emulator.run(1_000_000);
+        assert!(emulator.is_halted());
+        emulator
+    }
+
+    pub(crate) fn assert_ram(emulator: &Emulator, expected: &[(usize, i16)]) {
+        for (address, value) in expected {
+            assert_eq!(*value, emulator.ram()[*address] as i16, "RAM[{}]", address);
+        }
+    }
+
+    #[test]
+    fn work_test() {
+        let mut writer = CodeWriter::new();
+        let mut diagnostics = Diagnostics::new(None);
+        let options = Options {
+            debug: true,
+            ..Default::default()
+        };
+        compile(
+            &["SimpleAdd.vm".to_string()],
+            &mut writer,
+            &options,
+            &mut diagnostics,
+        );
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn function_test() {
+        let mut writer = CodeWriter::new();
+        let depths = compile(
+            &["SimpleFunction.vm".to_string()],
+            &mut writer,
+            &Options::default(),
+            &mut Diagnostics::new(None),
+        );
+        let actual = writer.finish().to_string();
+        assert!(actual.contains("(SimpleFunction.test)"));
+        assert!(actual.contains("// goto RET"));
+        // the depths come from the checks of the same pass
+        assert_eq!(vec![("SimpleFunction.test".to_string(), 2)], depths);
+    }
+
+    #[test]
+    fn bootstrap_test() {
+        let mut writer = CodeWriter::new();
+        let options = Options {
+            entry: Some("SimpleFunction.test".to_string()),
+            ..Default::default()
+        };
+        compile(
+            &["SimpleFunction.vm".to_string()],
+            &mut writer,
+            &options,
+            &mut Diagnostics::new(None),
+        );
+        let actual = writer.finish().to_string();
+        // the bootstrap comes before any translated command
+        let call = actual.find("@SimpleFunction.test\n0;JMP").unwrap();
+        let function = actual.find("(SimpleFunction.test)").unwrap();
+        assert!(call < function);
+    }
+
+    #[test]
+    fn diagnostics_test() {
+        let inputs = vec![
+            "InvalidCommands.vm".to_string(),
+            "NotFound.vm".to_string(),
+            "SimpleAdd.vm".to_string(),
+        ];
+
+        let mut diagnostics = Diagnostics::new(None);
+        let mut writer = CodeWriter::new();
+        compile(&inputs, &mut writer, &Options::default(), &mut diagnostics);
+        // 8 broken lines and a missing file
+        assert_eq!(9, diagnostics.errors().len());
+        assert!(diagnostics
+            .to_string()
+            .ends_with("error: aborting due to 9 previous errors\n"));
+        assert!(!diagnostics.to_string().contains("too many errors"));
+
+        // exactly as many errors as allowed, nothing is dropped
+        let mut diagnostics = Diagnostics::new(Some(9));
+        let mut writer = CodeWriter::new();
+        compile(&inputs, &mut writer, &Options::default(), &mut diagnostics);
+        assert_eq!(9, diagnostics.errors().len());
+        assert!(diagnostics.is_full());
+        assert!(!diagnostics.to_string().contains("too many errors"));
+
+        let mut diagnostics = Diagnostics::new(Some(3));
+        let mut writer = CodeWriter::new();
+        compile(&inputs, &mut writer, &Options::default(), &mut diagnostics);
+        assert_eq!(3, diagnostics.errors().len());
+        assert!(diagnostics.is_full());
+        assert!(diagnostics
+            .to_string()
+            .contains("error: too many errors, stopped after 3\n"));
+
+        // the first error still fails the run
+        let mut diagnostics = Diagnostics::new(Some(0));
+        let mut writer = CodeWriter::new();
+        compile(&inputs, &mut writer, &Options::default(), &mut diagnostics);
+        assert_eq!(1, diagnostics.errors().len());
+        assert!(!diagnostics.is_empty());
+    }
+
+    #[test]
+    fn translate_test() {
+        let actual = translate(
+            &[
+                (
+                    "dir/Main.vm",
+                    "function Main.main 0\npush constant 1\npop static 0\n",
+                ),
+                ("Sys.vm", "function Sys.init 0\ncall Main.main 0\n"),
+            ],
+            &Options {
+                entry: Some("Sys.init".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(actual.starts_with("\n// SP = 256\n"));
+        assert!(actual.contains("@Main.vm.0\nM=D"));
+        assert!(actual.contains("(Sys.init)"));
+
+        let diagnostics = translate(
+            &[("Main.vm", "push constant 1\nadd 1\npop x 0\n")],
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert_eq!(2, diagnostics.errors().len());
+        assert_eq!(2, diagnostics.errors()[0].span().unwrap().line);
+    }
+
+    #[test]
+    fn check_test() {
+        // labels of one function are not visible in another
+        let source = "function Main.f 0\nlabel L\ngoto L\nfunction Main.g 0\ngoto L\n";
+        let diagnostics = translate(&[("Main.vm", source)], &Options::default()).unwrap_err();
+        assert!(matches!(
+            diagnostics.errors(),
+            [TranslateError::UndefinedLabel(span)] if span.line == 5 && span.text == "L"
+        ));
+
+        let diagnostics = translate(
+            &[(
+                "Main.vm",
+                "function Main.f 0\nlabel A\nlabel A\npush constant 0\nreturn\n",
+            )],
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert_eq!(1, diagnostics.errors().len());
+        assert_eq!(1, diagnostics.warnings().len());
+
+        // a single file may call functions of files translated on their own
+        let source = [(
+            "Main.vm",
+            "function Main.main 0\npush constant 2\npush constant 3\ncall Math.multiply 2\nreturn\n",
+        )];
+        assert!(translate(&source, &Options::default()).is_ok());
+        let options = Options {
+            entry: Some("Main.main".to_string()),
+            ..Default::default()
+        };
+        let diagnostics = translate(&source, &options).unwrap_err();
+        assert!(matches!(
+            diagnostics.errors(),
+            [TranslateError::UndefinedFunction(span)] if span.text == "Math.multiply"
+        ));
+    }
+
+    #[test]
+    fn simple_add_test() {
+        let options = Options {
+            debug: true,
+            ..Default::default()
+        };
+        let emulator = run_vm(&[("SimpleAdd.vm", &read("SimpleAdd.vm"))], &options, &[]);
+        assert_ram(&emulator, &[(0, 257), (256, 15)]);
+    }
+
+    #[test]
+    fn stack_test() {
+        let options = Options {
+            debug: true,
+            ..Default::default()
+        };
+        let emulator = run_vm(&[("StackTest.vm", &read("StackTest.vm"))], &options, &[]);
+        assert_ram(
+            &emulator,
+            &[
+                (0, 266),
+                (256, -1),
+                (257, 0),
+                (258, 0),
+                (259, 0),
+                (260, -1),
+                (261, 0),
+                (262, -1),
+                (263, 0),
+                (264, 0),
+                (265, -91),
+            ],
+        );
+    }
+
+    #[test]
+    fn static_test() {
+        let options = Options {
+            debug: true,
+            ..Default::default()
+        };
+        let emulator = run_vm(&[("StaticTest.vm", &read("StaticTest.vm"))], &options, &[]);
+        assert_ram(&emulator, &[(256, 1110)]);
+    }
+
+    #[test]
+    fn basic_loop_test() {
+        let emulator = run_vm(
+            &[("BasicLoop.vm", &read("BasicLoop.vm"))],
+            &Options::default(),
+            &[(0, 256), (1, 300), (2, 400), (400, 3)],
+        );
+        assert_ram(&emulator, &[(0, 257), (256, 6)]);
+    }
+
+    #[test]
+    fn simple_function_test() {
+        let emulator = run_vm(
+            &[("SimpleFunction.vm", &read("SimpleFunction.vm"))],
+            &Options::default(),
+            &[
+                (0, 317),
+                (1, 317),
+                (2, 310),
+                (3, 3000),
+                (4, 4000),
+                (310, 1234),
+                (311, 37),
+                (312, 1000),
+                (313, 305),
+                (314, 300),
+                (315, 3010),
+                (316, 4010),
+            ],
+        );
+        assert_ram(
+            &emulator,
+            &[
+                (0, 311),
+                (1, 305),
+                (2, 300),
+                (3, 3010),
+                (4, 4010),
+                (310, 1196),
+            ],
+        );
+    }
+
+    #[test]
+    fn fibonacci_element_test() {
+        for optimize_size in [false, true] {
+            for opt_level in 0..=3 {
+                let options = Options {
+                    entry: Some("Sys.init".to_string()),
+                    opt_level,
+                    optimize_size,
+                    ..Default::default()
+                };
+                let emulator = run_vm(
+                    &[
+                        ("Main.vm", &read("FibonacciElement/Main.vm")),
+                        ("Sys.vm", &read("FibonacciElement/Sys.vm")),
+                    ],
+                    &options,
+                    &[],
+                );
+                assert_ram(&emulator, &[(0, 262), (261, 3)]);
+            }
+        }
+    }
+
+    #[test]
+    fn statics_test() {
+        for optimize_size in [false, true] {
+            for opt_level in 0..=3 {
+                let options = Options {
+                    entry: Some("Sys.init".to_string()),
+                    opt_level,
+                    optimize_size,
+                    ..Default::default()
+                };
+                let emulator = run_vm(
+                    &[
+                        ("Class1.vm", &read("StaticsTest/Class1.vm")),
+                        ("Class2.vm", &read("StaticsTest/Class2.vm")),
+                        ("Sys.vm", &read("StaticsTest/Sys.vm")),
+                    ],
+                    &options,
+                    &[],
+                );
+                assert_ram(&emulator, &[(0, 263), (261, -2), (262, 8)]);
+            }
+        }
+    }
+
+    #[test]
+    fn nested_call_test() {
+        for optimize_size in [false, true] {
+            for opt_level in 0..=3 {
+                let options = Options {
+                    entry: Some("Sys.init".to_string()),
+                    opt_level,
+                    optimize_size,
+                    ..Default::default()
+                };
+                let emulator = run_vm(&[("Sys.vm", &read("NestedCall/Sys.vm"))], &options, &[]);
+                assert_ram(
+                    &emulator,
+                    &[
+                        (0, 261),
+                        (1, 261),
+                        (2, 256),
+                        (3, 4000),
+                        (4, 5000),
+                        (5, 135),
+                        (6, 246),
+                    ],
+                );
+            }
+        }
+    }
+}
@@ -1,124 +1,315 @@
-use std::{
-    fs::{self, File},
-    io::{BufWriter, Write},
-    path::Path,
-};
-
-use clap::Parser;
-use log::{debug, info};
-use writer::CodeWriter;
+use std::{fs, path::Path, process};
 
-mod parser;
-mod template;
-mod writer;
+use clap::{ArgEnum, Parser, Subcommand};
+use log::info;
+use vmtrans::{
+    assembler, compile,
+    interpreter::{Interpreter, STACK_BASE},
+    optimize,
+    sourcemap::{self, SourceMap},
+    tst, CodeWriter, Diagnostics, Options, TranslateError, DEFAULT_ENTRY,
+};
 
 #[derive(Parser, Debug)]
+#[clap(subcommand_negates_reqs = true)]
 struct Args {
-    #[clap(short)]
-    input: String,
-    #[clap(short)]
-    out: String,
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(short, required = true)]
+    input: Option<String>,
+    #[clap(short, required = true)]
+    out: Option<String>,
     #[clap(short)]
     debug: bool,
+    /// do not emit the bootstrap code (SP=256, call of the entry function)
+    #[clap(long)]
+    no_init: bool,
+    /// entry function called by the bootstrap code [default: Sys.init]
+    #[clap(long)]
+    entry: Option<String>,
+    /// stop after this many errors are reported [default: unlimited]
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_errors: Option<usize>,
+    /// kind of output file
+    #[clap(long, arg_enum, default_value = "asm")]
+    emit: Emit,
+    /// optimization level, 1: peephole optimizer, 2: also VM-level constant folding,
+    /// 3: also stack top cached in D
+    #[clap(short = 'O', default_value = "0", value_parser = clap::value_parser!(u8).range(0..=3))]
+    opt_level: u8,
+    /// share subroutines for comparisons, call and return, for programs which do not fit in ROM
+    #[clap(long)]
+    optimize_size: bool,
+    /// comment each command with its VM file, line and text, `--annotate=counts` also with its
+    /// number of instructions
+    #[clap(
+        long,
+        arg_enum,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "source"
+    )]
+    annotate: Option<Annotate>,
+    /// emit no comments or blank lines
+    #[clap(long, conflicts_with = "annotate")]
+    strip: bool,
+    /// also write a JSON map from each ROM address to its VM file, line and command
+    #[clap(long, value_name = "FILE")]
+    source_map: Option<String>,
+    /// warn about functions never called, directly or not, from the entry function
+    #[clap(long)]
+    warn_unreachable: bool,
+    /// print the maximum working stack depth of each function
+    #[clap(long)]
+    stack_depth: bool,
 }
 
-fn compile<W: Write>(inputs: Vec<String>, output: &mut CodeWriter<W>, debug: bool) {
-    // only for debugging to init Stack Pointer
-    if debug {
-        output.debug();
-    }
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum Annotate {
+    /// file, line and VM text
+    Source,
+    /// also the number of instructions
+    Counts,
+}
 
-    for f in inputs {
-        let mut parser = parser::Parser::new(&f);
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum Emit {
+    /// Hack assembly text
+    Asm,
+    /// machine code loadable by the CPU emulator
+    Hack,
+}
 
-        // to remove slash
-        let path = Path::new(&f);
-        output.setFileName(&path.file_name().unwrap().to_str().unwrap());
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// run a .tst script on the CPU emulator and compare its output with the .cmp file
+    Test { script: String },
+    /// execute VM code directly and print the stack and RAM cells when it halts
+    Run {
+        /// .vm file or directory of .vm files
+        input: String,
+        /// do not run the bootstrap (SP=256, call of the entry function)
+        #[clap(long)]
+        no_init: bool,
+        /// entry function called by the bootstrap [default: Sys.init]
+        #[clap(long)]
+        entry: Option<String>,
+        /// stop after executing this many commands
+        #[clap(long, default_value = "1000000")]
+        max_steps: usize,
+        /// RAM cells printed at exit, e.g. `--ram 0 --ram 256..260`
+        #[clap(long)]
+        ram: Vec<String>,
+        /// RAM cells set before running, e.g. `--set 1=300`
+        #[clap(long)]
+        set: Vec<String>,
+    },
+}
 
-        loop {
-            if !parser.has_next_cmd() {
-                break;
-            }
+fn collect_inputs(input: &str) -> Result<Vec<String>, TranslateError> {
+    let io_error = |e| TranslateError::Io {
+        path: input.to_string(),
+        source: e,
+    };
 
-            parser.advance();
-
-            match parser.get_command_type() {
-                parser::CommandType::C_ARITHMETIC => output.writeArithmetic(parser.get_arg_1()),
-                parser::CommandType::C_PUSH | parser::CommandType::C_POP => output.writePushPop(
-                    parser.get_command_type(),
-                    parser.get_arg_1(),
-                    parser.get_arg_2(),
-                ),
-                parser::CommandType::C_LABEL => {
-                    output.writeLabel(parser.get_command_type(), parser.get_arg_1())
-                }
-                parser::CommandType::C_GOTO=> {
-                    output.writeGoto(parser.get_command_type(), parser.get_arg_1())
+    let mut files = vec![];
+    if Path::new(input).is_dir() {
+        for entry in fs::read_dir(input).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            match path.extension() {
+                Some(ext) if ext == "vm" => {
+                    info!("load vm file {:?}", path);
+                    files.push(path.to_string_lossy().to_string());
                 }
-                parser::CommandType::C_IF=> {
-                    output.writeIf(parser.get_command_type(), parser.get_arg_1())
+                _ => {
+                    // do nothing for folders and other files
                 }
-                parser::CommandType::C_FUNCTION => todo!(),
-                parser::CommandType::C_RETURN => todo!(),
-                parser::CommandType::C_CALL => todo!(),
             }
         }
+    } else {
+        info!("load vm file {}", input);
+        files.push(input.to_string());
     }
+
+    if files.is_empty() {
+        return Err(TranslateError::NoInput(input.to_string()));
+    }
+    // read_dir does not guarantee any order
+    files.sort();
+    Ok(files)
 }
 
-fn main() {
-    env_logger::init();
+/// Bootstrap is needed for whole programs (directories) or when an entry is given explicitly.
+fn bootstrap_entry(input: &str, no_init: bool, entry: Option<String>) -> Option<String> {
+    let is_dir = Path::new(input).is_dir();
+    if no_init || (!is_dir && entry.is_none()) {
+        None
+    } else {
+        Some(entry.unwrap_or_else(|| DEFAULT_ENTRY.to_string()))
+    }
+}
 
-    let args = Args::parse();
+fn run(args: Args) -> Result<Diagnostics, TranslateError> {
+    // both are required by clap when no subcommand is given
+    let input = args.input.unwrap_or_default();
+    let out = args.out.unwrap_or_default();
+    let files = collect_inputs(&input)?;
 
-    let mut files = vec![];
-    if Path::new(&args.input).is_dir() {
-        for entry in fs::read_dir(&args.input).unwrap() {
-            if let Ok(e) = entry {
-                let path = e.path();
-                match path.extension() {
-                    Some(ext) => {
-                        if ext == "vm" {
-                            info!("load vm file {:?}", path);
-                            files.push(path.to_str().unwrap().to_string());
-                        }
-                    }
-                    None => {
-                        // do nothing for folders
-                    }
-                }
-            } else {
-                panic!("panic");
-            }
+    let options = Options {
+        entry: bootstrap_entry(&input, args.no_init, args.entry),
+        debug: args.debug,
+        max_errors: args.max_errors,
+        opt_level: args.opt_level,
+        optimize_size: args.optimize_size,
+        annotate: args.annotate.is_some(),
+        warn_unreachable: args.warn_unreachable,
+    };
+
+    let mut diagnostics = Diagnostics::new(options.max_errors);
+    let mut writer = CodeWriter::new();
+    let depths = compile(&files, &mut writer, &options, &mut diagnostics);
+
+    if !diagnostics.is_empty() {
+        // do not leave an output of a previous run behind
+        let _ = fs::remove_file(&out);
+        return Ok(diagnostics);
+    }
+    let mut asm = optimize(writer.finish(), &options);
+    if args.strip {
+        asm = asm.without_comments();
+    }
+    // the source map numbers the lines as they are printed
+    if let Some(path) = &args.source_map {
+        fs::write(path, SourceMap::from_asm(&asm, options.annotate).to_json())?;
+    }
+    let output = match args.emit {
+        Emit::Asm if args.annotate == Some(Annotate::Counts) => sourcemap::annotate_counts(&asm),
+        Emit::Asm if options.annotate => asm.to_string(),
+        Emit::Asm => asm.without_origins().to_string(),
+        Emit::Hack => assembler::to_hack(&assembler::assemble(&asm.to_string())?),
+    };
+    fs::write(&out, output)?;
+    if args.stack_depth {
+        for (function, depth) in depths {
+            println!("{}: {}", function, depth);
         }
-    } else {
-        info!("load vm file {}", args.input);
-        files.push(args.input);
     }
+    Ok(diagnostics)
+}
 
-    if files.len() == 0 {
-        panic!("no length");
+fn run_test(script: &str) {
+    match tst::run_file(Path::new(script)) {
+        Ok(report) => match report.mismatch {
+            None => println!("{}: End of script - Comparison ended successfully", script),
+            Some(m) => {
+                eprintln!("{}: Comparison failure at line {}", script, m.line);
+                eprintln!("expected: {}", m.expected);
+                eprintln!("  actual: {}", m.actual);
+                process::exit(1);
+            }
+        },
+        Err(e) => {
+            eprintln!("error: {}: {}", script, e);
+            process::exit(1);
+        }
     }
+}
 
-    let file = File::create(args.out).unwrap();
-    let mut writer = BufWriter::new(file);
-    let mut writer = writer::CodeWriter::new(writer);
-    compile(files, &mut writer, args.debug);
+/// Parses `N` or `N..M` (exclusive) RAM addresses.
+fn parse_range(range: &str) -> Option<std::ops::Range<usize>> {
+    match range.split_once("..") {
+        Some((start, end)) => Some(start.parse().ok()?..end.parse().ok()?),
+        None => {
+            let address = range.parse().ok()?;
+            Some(address..address + 1)
+        }
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::io::{BufReader, BufWriter, Read};
+fn run_vm(
+    input: &str,
+    entry: Option<String>,
+    max_steps: usize,
+    ram: &[String],
+    set: &[String],
+) -> Result<(), String> {
+    let files = collect_inputs(input).map_err(|e| e.to_string())?;
+    let options = Options {
+        // single files without bootstrap still get a stack
+        debug: entry.is_none(),
+        entry,
+        ..Default::default()
+    };
+    let mut interpreter = Interpreter::from_files(&files, &options).map_err(|d| d.to_string())?;
+    for cell in set {
+        let (address, value) = cell
+            .split_once('=')
+            .and_then(|(a, v)| Some((a.parse::<usize>().ok()?, v.parse::<i16>().ok()?)))
+            .filter(|(a, _)| *a < interpreter.ram().len())
+            .ok_or_else(|| format!("error: invalid --set `{}`\n", cell))?;
+        interpreter.ram_mut()[address] = value as u16;
+    }
 
-    use crate::{compile, writer::CodeWriter};
+    let steps = interpreter
+        .run(max_steps)
+        .map_err(|e| format!("error: {}\n", e))?;
+    if !interpreter.is_halted() {
+        eprintln!("warning: stopped after {} steps", steps);
+    }
+    println!("steps: {}", steps);
+    let stack: Vec<String> = interpreter
+        .stack(STACK_BASE)
+        .iter()
+        .map(|v| (*v as i16).to_string())
+        .collect();
+    println!("stack: [{}]", stack.join(", "));
+    for range in ram {
+        let range = parse_range(range)
+            .filter(|r| r.end <= interpreter.ram().len())
+            .ok_or_else(|| format!("error: invalid --ram `{}`\n", range))?;
+        for address in range {
+            println!("RAM[{}] = {}", address, interpreter.ram()[address] as i16);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    env_logger::init();
 
-    #[test]
-    fn work_test() {
-        let mut actual = vec![];
-        {
-            let mut writer = BufWriter::new(&mut actual);
-            let mut writer = CodeWriter::new(writer);
-            compile(vec!["SimpleAdd.vm".to_string()], &mut writer, true);
+    let args = Args::parse();
+    match &args.command {
+        Some(Command::Test { script }) => {
+            run_test(script);
+            return;
+        }
+        Some(Command::Run {
+            input,
+            no_init,
+            entry,
+            max_steps,
+            ram,
+            set,
+        }) => {
+            let entry = bootstrap_entry(input, *no_init, entry.clone());
+            if let Err(e) = run_vm(input, entry, *max_steps, ram, set) {
+                eprint!("{}", e);
+                process::exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
+    match run(args) {
+        // only warnings
+        Ok(diagnostics) if diagnostics.is_empty() => eprint!("{}", diagnostics),
+        Ok(diagnostics) => {
+            eprint!("{}", diagnostics);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprint!("{}", e);
+            process::exit(1);
         }
     }
 }
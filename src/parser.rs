@@ -1,24 +1,30 @@
 use std::{
     fs::File,
     io::{BufRead, BufReader, Lines},
-    iter::Peekable,
+    iter::{Enumerate, Peekable},
+    path::Path,
     str::FromStr,
 };
 
 use log::debug;
 use regex::Regex;
 
-pub struct Parser {
-    line: Peekable<Lines<BufReader<File>>>,
-    curr_command: Option<String>,
+use crate::{
+    command::{ArithOp, Segment, Spanned, VmCommand},
+    error::{Diagnostics, SourceSpan, TranslateError},
+};
 
-    cmd_type: Option<CommandType>,
-    arg1: Option<String>,
-    arg2: Option<i64>,
+/// Reads VM commands line by line, yielding one `Spanned<VmCommand>` per command.
+///
+/// A malformed line yields an error and the parser resumes at the next line.
+pub struct Parser<R: BufRead = BufReader<File>> {
+    filepath: String,
+    line: Peekable<Enumerate<Lines<R>>>,
     trim_comment_regex: Regex,
 }
 
 use strum_macros::EnumString;
+#[allow(non_camel_case_types)]
 #[derive(PartialEq, Debug, EnumString)]
 pub enum CommandType {
     #[strum(
@@ -47,164 +53,401 @@ pub enum CommandType {
     C_FUNCTION,
     #[strum(serialize = "return")]
     C_RETURN,
-    #[strum(serialize = "label")]
+    #[strum(serialize = "call")]
     C_CALL,
 }
 
 impl Parser {
-    pub fn new(filepath: &str) -> Parser {
-        let f = File::open(filepath).unwrap();
-        let reader = BufReader::new(f);
+    pub fn new(filepath: &str) -> Result<Parser, TranslateError> {
+        let f = File::open(filepath).map_err(|e| TranslateError::Io {
+            path: filepath.to_string(),
+            source: e,
+        })?;
+        Ok(Parser::from_reader(filepath, BufReader::new(f)))
+    }
+}
+
+impl<'a> Parser<&'a [u8]> {
+    /// parse VM code held in memory, `name` is used in diagnostics
+    pub fn from_source(name: &str, source: &'a str) -> Parser<&'a [u8]> {
+        Parser::from_reader(name, source.as_bytes())
+    }
+}
+
+impl<R: BufRead> Parser<R> {
+    pub fn from_reader(name: &str, reader: R) -> Parser<R> {
         Parser {
-            line: reader.lines().peekable(),
-            curr_command: None,
+            filepath: name.to_string(),
+            line: reader.lines().enumerate().peekable(),
             trim_comment_regex: Regex::new(r"(.*)\s*//.*").unwrap(),
-            cmd_type: None,
-            arg1: None,
-            arg2: None,
         }
     }
 
-    pub fn advance(&mut self) {
-        loop {
-            match self.line.next() {
-                Some(l) => match l {
-                    Ok(cmd_cand) => {
-                        let trimmed_line = cmd_cand.trim();
-                        if trimmed_line.len() > 0 && !trimmed_line.starts_with("//") {
-                            let cmd = match self.trim_comment_regex.captures(trimmed_line) {
-                                Some(caps) => caps.get(1).unwrap().as_str().trim(),
-                                None => trimmed_line.trim(),
-                            };
-
-                            self.curr_command = Some(cmd.to_string());
-
-                            if let Some(cmd) = &self.curr_command {
-                                let vm_cmd: Vec<&str> = cmd.split(" ").collect();
-                                debug!("{:?}", vm_cmd);
-                                self.cmd_type = Some(CommandType::from_str(vm_cmd[0]).unwrap());
-                                match self.cmd_type.as_ref().unwrap() {
-                                    CommandType::C_ARITHMETIC => {
-                                        self.arg1 = Some(vm_cmd[0].to_string());
-                                    }
-                                    CommandType::C_PUSH | CommandType::C_POP => {
-                                        self.arg1 = Some(vm_cmd[1].to_string());
-                                        self.arg2 = Some(i64::from_str(vm_cmd[2]).unwrap());
-                                    }
-                                    CommandType::C_LABEL
-                                    | CommandType::C_GOTO
-                                    | CommandType::C_IF => {
-                                        self.arg1 = Some(vm_cmd[1].to_string());
-                                    }
-                                    CommandType::C_RETURN => todo!(),
-                                    CommandType::C_FUNCTION => todo!(),
-                                    CommandType::C_CALL => todo!(),
-                                }
-                            }
-                            break;
-                        }
-                    }
-                    Err(e) => panic!("cannot read line. e:{}", e),
-                },
-                None => {
-                    debug!("finish reading.");
-                    self.curr_command = None;
+    pub fn filepath(&self) -> &str {
+        &self.filepath
+    }
+
+    fn parse_command(
+        &self,
+        line_no: usize,
+        source_line: &str,
+        cmd: &str,
+    ) -> Result<Spanned<VmCommand>, TranslateError> {
+        // keep the column of each word for diagnostics
+        let vm_cmd: Vec<(usize, &str)> = cmd
+            .split_whitespace()
+            .map(|w| (w.as_ptr() as usize - source_line.as_ptr() as usize + 1, w))
+            .collect();
+        debug!("{:?}", vm_cmd);
+        let span = |(column, text): (usize, &str)| SourceSpan {
+            path: self.filepath.clone(),
+            line: line_no,
+            column,
+            text: text.to_string(),
+            source_line: source_line.to_string(),
+        };
+
+        let cmd_type = CommandType::from_str(vm_cmd[0].1)
+            .map_err(|_| TranslateError::UnknownCommand(span(vm_cmd[0])))?;
+
+        let expected = match cmd_type {
+            CommandType::C_ARITHMETIC | CommandType::C_RETURN => "no arguments",
+            CommandType::C_PUSH | CommandType::C_POP => "a segment and an index",
+            CommandType::C_LABEL | CommandType::C_GOTO | CommandType::C_IF => "a label",
+            CommandType::C_FUNCTION => "a function name and the number of local variables",
+            CommandType::C_CALL => "a function name and the number of arguments",
+        };
+        let n_args = match cmd_type {
+            CommandType::C_ARITHMETIC | CommandType::C_RETURN => 0,
+            CommandType::C_LABEL | CommandType::C_GOTO | CommandType::C_IF => 1,
+            _ => 2,
+        };
+        if vm_cmd.len() < n_args + 1 {
+            return Err(TranslateError::MissingArgument {
+                span: span(vm_cmd[0]),
+                expected,
+            });
+        }
+        if vm_cmd.len() > n_args + 1 {
+            return Err(TranslateError::UnexpectedArgument(span(vm_cmd[n_args + 1])));
+        }
+
+        let command = match cmd_type {
+            CommandType::C_ARITHMETIC => {
+                VmCommand::Arithmetic(ArithOp::from_str(vm_cmd[0].1).unwrap())
+            }
+            CommandType::C_PUSH | CommandType::C_POP => {
+                let segment = Segment::from_str(vm_cmd[1].1)
+                    .map_err(|_| TranslateError::UnknownSegment(span(vm_cmd[1])))?;
+                let index = parse_index(vm_cmd[2], segment.max_index(), span)?;
+                if cmd_type == CommandType::C_PUSH {
+                    VmCommand::Push { segment, index }
+                } else if segment == Segment::Constant {
+                    return Err(TranslateError::PopConstant(span(vm_cmd[1])));
+                } else {
+                    VmCommand::Pop { segment, index }
                 }
             }
-        }
+            CommandType::C_LABEL => VmCommand::Label(parse_name(vm_cmd[1], span)?),
+            CommandType::C_GOTO => VmCommand::Goto(parse_name(vm_cmd[1], span)?),
+            CommandType::C_IF => VmCommand::IfGoto(parse_name(vm_cmd[1], span)?),
+            CommandType::C_FUNCTION => VmCommand::Function {
+                name: parse_name(vm_cmd[1], span)?,
+                n_vars: parse_index(vm_cmd[2], MAX_INDEX, span)?,
+            },
+            CommandType::C_CALL => VmCommand::Call {
+                name: parse_name(vm_cmd[1], span)?,
+                n_args: parse_index(vm_cmd[2], MAX_INDEX, span)?,
+            },
+            CommandType::C_RETURN => VmCommand::Return,
+        };
 
-        debug!("curr_command: {:?}", self.curr_command);
+        Ok(Spanned {
+            node: command,
+            span: span((vm_cmd[0].0, cmd)),
+        })
     }
+}
+
+impl<R: BufRead> Iterator for Parser<R> {
+    type Item = Result<Spanned<VmCommand>, TranslateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (line_no, cmd_cand) = match self.line.next()? {
+                (i, Ok(l)) => (i + 1, l),
+                (_, Err(e)) => {
+                    return Some(Err(TranslateError::Io {
+                        path: self.filepath.clone(),
+                        source: e,
+                    }))
+                }
+            };
 
-    pub fn get_command_type(&self) -> &CommandType {
-        self.cmd_type.as_ref().unwrap()
+            let trimmed_line = cmd_cand.trim();
+            if !trimmed_line.is_empty() && !trimmed_line.starts_with("//") {
+                let cmd = match self.trim_comment_regex.captures(trimmed_line) {
+                    Some(caps) => caps.get(1).unwrap().as_str().trim(),
+                    None => trimmed_line.trim(),
+                };
+                let command = self.parse_command(line_no, &cmd_cand, cmd);
+                debug!("curr_command: {:?}", command);
+                return Some(command);
+            }
+        }
     }
+}
 
-    pub fn get_arg_1(&self) -> &String {
-        self.arg1.as_ref().unwrap()
+/// Parses a whole file, reporting the malformed lines to `diagnostics`.
+///
+/// Returns the file name without its directory, and the well-formed commands.
+pub fn parse_all<R: BufRead>(
+    parser: Parser<R>,
+    diagnostics: &mut Diagnostics,
+) -> (String, Vec<Spanned<VmCommand>>) {
+    let file = Path::new(parser.filepath())
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let mut commands = vec![];
+    for command in parser {
+        match command {
+            Ok(command) => commands.push(command),
+            Err(e) => diagnostics.report(e),
+        }
     }
+    (file, commands)
+}
+
+// the largest value an A-instruction can load
+const MAX_INDEX: u16 = 32767;
 
-    pub fn get_arg_2(&self) -> i64 {
-        self.arg2.unwrap()
+fn parse_index<F>(word: (usize, &str), max: u16, span: F) -> Result<u16, TranslateError>
+where
+    F: Fn((usize, &str)) -> SourceSpan,
+{
+    let index = u16::from_str(word.1).map_err(|_| TranslateError::InvalidIndex(span(word)))?;
+    if index > max {
+        return Err(TranslateError::IndexOutOfRange {
+            span: span(word),
+            max,
+        });
     }
+    Ok(index)
+}
 
-    pub fn has_next_cmd(&mut self) -> bool {
-        match self.line.peek() {
-            Some(_) => return true,
-            None => return false,
-        }
+/// Label or function name: letters, digits, `_`, `.` and `:`, not beginning with a digit.
+///
+/// `$` is left to the code writer, which builds its own labels with it.
+fn parse_name<F>(word: (usize, &str), span: F) -> Result<String, TranslateError>
+where
+    F: Fn((usize, &str)) -> SourceSpan,
+{
+    let valid = !word.1.starts_with(|c: char| c.is_ascii_digit())
+        && word
+            .1
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.:".contains(c));
+    if !valid {
+        return Err(TranslateError::InvalidName(span(word)));
     }
+    Ok(word.1.to_string())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::parser::CommandType;
+    use std::io::BufRead;
+
+    use crate::command::{ArithOp, Segment, VmCommand};
 
     use super::Parser;
 
+    fn next_command<R: BufRead>(parser: &mut Parser<R>) -> VmCommand {
+        parser.next().unwrap().unwrap().node
+    }
+
+    fn push(segment: Segment, index: u16) -> VmCommand {
+        VmCommand::Push { segment, index }
+    }
+
     #[test]
     fn work_test() {
         // env_logger::init();
 
-        let mut parser = Parser::new("SimpleAdd.vm");
-        parser.advance();
-        parser.has_next_cmd();
-        assert_eq!(&CommandType::C_PUSH, parser.get_command_type());
-        assert_eq!("constant", parser.get_arg_1());
-        assert_eq!(7, parser.get_arg_2());
+        let mut parser = Parser::new("SimpleAdd.vm").unwrap();
+        assert_eq!(push(Segment::Constant, 7), next_command(&mut parser));
+        assert_eq!(push(Segment::Constant, 8), next_command(&mut parser));
+        assert_eq!(
+            VmCommand::Arithmetic(ArithOp::Add),
+            next_command(&mut parser)
+        );
+    }
 
-        parser.advance();
-        parser.has_next_cmd();
-        assert_eq!(&CommandType::C_PUSH, parser.get_command_type());
-        assert_eq!("constant", parser.get_arg_1());
-        assert_eq!(8, parser.get_arg_2());
+    #[test]
+    fn work_test_stack() {
+        let _ = env_logger::try_init();
 
-        parser.advance();
-        parser.has_next_cmd();
-        parser.get_command_type();
-        assert_eq!(&CommandType::C_ARITHMETIC, parser.get_command_type());
-        assert_eq!("add", parser.get_arg_1());
+        let mut parser = Parser::new("StackTest.vm").unwrap();
+        assert_eq!(push(Segment::Constant, 17), next_command(&mut parser));
+        assert_eq!(push(Segment::Constant, 17), next_command(&mut parser));
+        assert_eq!(
+            VmCommand::Arithmetic(ArithOp::Eq),
+            next_command(&mut parser)
+        );
     }
 
     #[test]
-    fn work_test_stack() {
-        env_logger::init();
+    fn work_test_label() {
+        let _ = env_logger::try_init();
 
-        let mut parser = Parser::new("StackTest.vm");
-        parser.advance();
-        parser.has_next_cmd();
-        assert_eq!(&CommandType::C_PUSH, parser.get_command_type());
-        assert_eq!("constant", parser.get_arg_1());
-        assert_eq!(17, parser.get_arg_2());
+        let mut parser = Parser::new("BasicLoop.vm").unwrap();
+        next_command(&mut parser);
+        next_command(&mut parser);
 
-        parser.advance();
-        parser.has_next_cmd();
-        assert_eq!(&CommandType::C_PUSH, parser.get_command_type());
-        assert_eq!("constant", parser.get_arg_1());
-        assert_eq!(17, parser.get_arg_2());
+        let label = parser.next().unwrap().unwrap();
+        assert_eq!(VmCommand::Label("LOOP_START".to_string()), label.node);
+        assert_eq!(11, label.span.line);
+        assert_eq!("label LOOP_START", label.span.text);
+    }
 
-        parser.advance();
-        parser.has_next_cmd();
-        parser.get_command_type();
-        assert_eq!(&CommandType::C_ARITHMETIC, parser.get_command_type());
-        assert_eq!("eq", parser.get_arg_1());
+    #[test]
+    fn work_test_function() {
+        let mut parser = Parser::new("SimpleFunction.vm").unwrap();
+        assert_eq!(
+            VmCommand::Function {
+                name: "SimpleFunction.test".to_string(),
+                n_vars: 2
+            },
+            next_command(&mut parser)
+        );
+        assert_eq!(
+            Some(VmCommand::Return),
+            parser.last().map(|c| c.unwrap().node)
+        );
     }
 
     #[test]
-    fn work_test_label() {
-        env_logger::init();
+    fn work_test_all_keywords() {
+        let mut parser = Parser::new("CommandTypes.vm").unwrap();
 
-        let mut parser = Parser::new("BasicLoop.vm");
-        parser.advance();
-        parser.has_next_cmd();
+        for op in [
+            ArithOp::Add,
+            ArithOp::Sub,
+            ArithOp::Neg,
+            ArithOp::Eq,
+            ArithOp::Gt,
+            ArithOp::Lt,
+            ArithOp::And,
+            ArithOp::Or,
+            ArithOp::Not,
+        ] {
+            assert_eq!(VmCommand::Arithmetic(op), next_command(&mut parser));
+        }
+        assert_eq!(push(Segment::Constant, 7), next_command(&mut parser));
+        assert_eq!(
+            VmCommand::Pop {
+                segment: Segment::Local,
+                index: 2
+            },
+            next_command(&mut parser)
+        );
+        assert_eq!(
+            VmCommand::Label("LOOP".to_string()),
+            next_command(&mut parser)
+        );
+        assert_eq!(
+            VmCommand::Goto("LOOP".to_string()),
+            next_command(&mut parser)
+        );
+        assert_eq!(
+            VmCommand::IfGoto("END".to_string()),
+            next_command(&mut parser)
+        );
+        assert_eq!(
+            VmCommand::Function {
+                name: "Main.main".to_string(),
+                n_vars: 3
+            },
+            next_command(&mut parser)
+        );
+        assert_eq!(
+            VmCommand::Call {
+                name: "Math.multiply".to_string(),
+                n_args: 2
+            },
+            next_command(&mut parser)
+        );
+        assert_eq!(VmCommand::Return, next_command(&mut parser));
 
-        parser.advance();
-        parser.has_next_cmd();
+        // trailing comments and blank lines are not commands
+        assert!(parser.next().is_none());
+    }
+
+    #[test]
+    fn error_test() {
+        use crate::error::TranslateError;
+
+        let mut parser = Parser::new("InvalidCommands.vm").unwrap();
+        let mut next_error = || parser.next().unwrap().unwrap_err();
+
+        let e = next_error();
+        assert!(matches!(e, TranslateError::UnknownCommand(_)));
+        assert_eq!(
+            "error: unknown command `pussh`\n --> InvalidCommands.vm:2:1\n  |\n2 | pussh constant 17\n  | ^^^^^\n",
+            e.to_string()
+        );
+        assert!(matches!(
+            next_error(),
+            TranslateError::MissingArgument { .. }
+        ));
+        let e = next_error();
+        assert!(matches!(e, TranslateError::UnexpectedArgument(_)));
+        assert_eq!(17, e.span().unwrap().column);
+        assert!(matches!(next_error(), TranslateError::UnknownSegment(_)));
+        assert!(matches!(next_error(), TranslateError::InvalidIndex(_)));
+        assert!(matches!(
+            next_error(),
+            TranslateError::IndexOutOfRange { max: 7, .. }
+        ));
+        assert!(matches!(next_error(), TranslateError::PopConstant(_)));
+        assert!(matches!(next_error(), TranslateError::InvalidIndex(_)));
+
+        // the parser keeps going after an error
+        assert_eq!(push(Segment::Constant, 1), next_command(&mut parser));
+    }
+
+    #[test]
+    fn name_test() {
+        use crate::error::TranslateError;
+
+        let source =
+            "label Main.loop:1_a\nlabel 1st\ngoto a$b\ncall Sys.init-x 0\nfunction $$eq 0\n";
+        let mut parser = Parser::from_source("Main.vm", source);
+        assert_eq!(
+            VmCommand::Label("Main.loop:1_a".to_string()),
+            next_command(&mut parser)
+        );
+        for column in [7, 6, 6, 10] {
+            let e = parser.next().unwrap().unwrap_err();
+            assert!(matches!(e, TranslateError::InvalidName(_)));
+            assert_eq!(column, e.span().unwrap().column);
+        }
+    }
+
+    #[test]
+    fn from_source_test() {
+        let mut parser = Parser::from_source("Main.vm", "// comment\npush constant 3\nneg\n");
+        assert_eq!(push(Segment::Constant, 3), next_command(&mut parser));
+        assert_eq!(
+            VmCommand::Arithmetic(ArithOp::Neg),
+            next_command(&mut parser)
+        );
+        assert!(parser.next().is_none());
 
-        parser.advance();
-        parser.has_next_cmd();
-        parser.get_command_type();
-        assert_eq!(&CommandType::C_LABEL, parser.get_command_type());
-        assert_eq!("LOOP_START", parser.get_arg_1());
+        let mut parser = Parser::from_source("Main.vm", "\n  popp local 0");
+        let e = parser.next().unwrap().unwrap_err();
+        assert_eq!("Main.vm", e.span().unwrap().path);
+        assert_eq!((2, 3), (e.span().unwrap().line, e.span().unwrap().column));
     }
 }
@@ -0,0 +1,242 @@
+//! Peephole optimizer over parsed Hack assembly (`-O1`).
+//!
+//! Rewrites only straight-line code between labels and jumps, so jump targets keep their meaning.
+//! It assumes the stack pointer never points to itself, i.e. a store through `@SP / A=M` never
+//! changes SP.
+
+use crate::assembler::{AValue, Comp, Dest, Instruction, Jump};
+
+/// What the A register is known to hold.
+#[derive(Clone, PartialEq)]
+enum AState {
+    Unknown,
+    /// loaded by `@value`
+    Value(AValue),
+    /// `@SP / A=M`, the address of the stack top
+    StackTop,
+}
+
+fn sp() -> AValue {
+    AValue::Symbol("SP".to_string())
+}
+
+/// `dest=comp`
+fn is_assign(instruction: &Instruction, dest: Dest, comp: Comp) -> bool {
+    *instruction
+        == Instruction::C {
+            dest: Some(dest),
+            comp,
+            jump: None,
+        }
+}
+
+fn is_sp(instruction: &Instruction) -> bool {
+    *instruction == Instruction::A(sp())
+}
+
+/// Applies all rewrites until none of them changes the code.
+pub fn optimize(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let tagged = instructions.into_iter().map(|i| ((), i)).collect();
+    optimize_tagged(tagged)
+        .into_iter()
+        .map(|(_, i)| i)
+        .collect()
+}
+
+/// [`optimize`] keeping a tag, e.g. the VM command, with each instruction.
+///
+/// Rewritten instructions keep the tag of the first instruction they replace.
+pub fn optimize_tagged<T: Clone>(mut instructions: Vec<(T, Instruction)>) -> Vec<(T, Instruction)> {
+    loop {
+        let before = instructions.len();
+        instructions = cancel_sp_pairs(instructions);
+        instructions = drop_redundant_loads(instructions);
+        instructions = drop_dead_d(instructions);
+        if instructions.len() == before {
+            return instructions;
+        }
+    }
+}
+
+/// `@SP / M=M+1 / @SP / AM=M-1` (push followed by pop) becomes `@SP / A=M`.
+fn cancel_sp_pairs<T: Clone>(instructions: Vec<(T, Instruction)>) -> Vec<(T, Instruction)> {
+    let mut out = Vec::with_capacity(instructions.len());
+    let mut i = 0;
+    while i < instructions.len() {
+        let window = &instructions[i..(i + 4).min(instructions.len())];
+        if window.len() == 4
+            && is_sp(&window[0].1)
+            && is_assign(&window[1].1, Dest::M, Comp::MPlusOne)
+            && is_sp(&window[2].1)
+            && is_assign(&window[3].1, Dest::AM, Comp::MMinusOne)
+        {
+            let tag = &window[0].0;
+            out.push((tag.clone(), Instruction::A(sp())));
+            out.push((
+                tag.clone(),
+                Instruction::C {
+                    dest: Some(Dest::A),
+                    comp: Comp::M,
+                    jump: None,
+                },
+            ));
+            i += 4;
+        } else {
+            out.push(instructions[i].clone());
+            i += 1;
+        }
+    }
+    out
+}
+
+/// Drops `@x` when A already holds x, and `@SP / A=M` when A already points to the stack top.
+fn drop_redundant_loads<T: Clone>(instructions: Vec<(T, Instruction)>) -> Vec<(T, Instruction)> {
+    let mut out = Vec::with_capacity(instructions.len());
+    let mut a = AState::Unknown;
+    let mut i = 0;
+    while i < instructions.len() {
+        let instruction = &instructions[i].1;
+        match instruction {
+            Instruction::Label(_) => a = AState::Unknown,
+            Instruction::A(value) => {
+                if a == AState::StackTop
+                    && is_sp(instruction)
+                    && matches!(instructions.get(i + 1), Some((_, next)) if is_assign(next, Dest::A, Comp::M))
+                {
+                    i += 2;
+                    continue;
+                }
+                if a == AState::Value(value.clone()) {
+                    i += 1;
+                    continue;
+                }
+                a = AState::Value(value.clone());
+            }
+            Instruction::C { dest, jump, .. } => {
+                if dest.is_some_and(Dest::writes_a) {
+                    a = if a == AState::Value(sp()) && is_assign(instruction, Dest::A, Comp::M) {
+                        AState::StackTop
+                    } else {
+                        AState::Unknown
+                    };
+                }
+                if *jump == Some(Jump::JMP) {
+                    // only reachable through a label
+                    a = AState::Unknown;
+                }
+            }
+        }
+        out.push(instructions[i].clone());
+        i += 1;
+    }
+    out
+}
+
+/// Drops `D=...` whose value is overwritten before it is read, and `D=M` right after `M=D`.
+fn drop_dead_d<T: Clone>(instructions: Vec<(T, Instruction)>) -> Vec<(T, Instruction)> {
+    let mut out: Vec<(T, Instruction)> = Vec::with_capacity(instructions.len());
+    for (i, (tag, instruction)) in instructions.iter().enumerate() {
+        if is_assign(instruction, Dest::D, Comp::M)
+            && matches!(out.last(), Some((_, prev)) if is_assign(prev, Dest::M, Comp::D))
+        {
+            continue;
+        }
+        if matches!(
+            instruction,
+            Instruction::C {
+                dest: Some(Dest::D),
+                jump: None,
+                ..
+            }
+        ) && is_dead_d(instructions[i + 1..].iter().map(|(_, i)| i))
+        {
+            continue;
+        }
+        out.push((tag.clone(), instruction.clone()));
+    }
+    out
+}
+
+/// True when D is written before it is read, within the straight-line code of `rest`.
+fn is_dead_d<'a>(rest: impl Iterator<Item = &'a Instruction>) -> bool {
+    for instruction in rest {
+        match instruction {
+            Instruction::Label(_) => return false,
+            Instruction::A(_) => {}
+            Instruction::C { dest, comp, jump } => {
+                if comp.reads_d() || jump.is_some() {
+                    return false;
+                }
+                if dest.is_some_and(Dest::writes_d) {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::optimize;
+    use crate::{
+        assembler::{parse, Instruction},
+        emulator::Emulator,
+        translate, Options,
+    };
+
+    fn optimize_asm(asm: &str) -> String {
+        let instructions: Vec<Instruction> =
+            parse(asm).unwrap().into_iter().map(|(_, i)| i).collect();
+        let lines: Vec<String> = optimize(instructions)
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        lines.join("\n")
+    }
+
+    #[test]
+    fn push_add_test() {
+        let options = Options::default();
+        let asm = translate(
+            &[("Main.vm", "push constant 7\npush constant 8\nadd\n")],
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            "@7\nD=A\n@SP\nA=M\nM=D\n@SP\nM=M+1\n@8\nD=A\n@SP\nA=M\nM=D\n@SP\nAM=M-1\nM=M+D\n@SP\nM=M+1",
+            optimize_asm(&asm)
+        );
+
+        let mut emulator = Emulator::from_asm(&optimize_asm(&asm)).unwrap();
+        emulator.ram_mut()[0] = 256;
+        emulator.run(100);
+        assert_eq!(257, emulator.ram()[0]);
+        assert_eq!(15, emulator.ram()[256]);
+    }
+
+    #[test]
+    fn label_test() {
+        // the second load is a jump target, so A is unknown there
+        assert_eq!(
+            "@SP\nM=M+1\n(L)\n@SP\nM=M-1",
+            optimize_asm("@SP\nM=M+1\n(L)\n@SP\nM=M-1")
+        );
+        assert_eq!("@SP\nM=M+1\nM=M-1", optimize_asm("@SP\nM=M+1\n@SP\nM=M-1"));
+    }
+
+    #[test]
+    fn dead_d_test() {
+        assert_eq!(
+            "@5\n@1\nD=A\n@2\nM=D",
+            optimize_asm("@5\nD=A\n@1\nD=A\n@2\nM=D")
+        );
+        // read by a jump
+        assert_eq!(
+            "@5\nD=A\n@END\nD;JGT\nD=A",
+            optimize_asm("@5\nD=A\n@END\nD;JGT\nD=A")
+        );
+        // D may be read at the label
+        assert_eq!("D=A\n(L)\nD=M", optimize_asm("D=A\n(L)\nD=M"));
+    }
+}
@@ -0,0 +1,285 @@
+//! Source maps from ROM addresses back to the VM commands they were translated from.
+//!
+//! The code writer marks the start of each command with a [`Line::Origin`]. Optimization keeps the
+//! origin of each instruction as its tag, and the map is built from the final [`Asm`]. With
+//! [`crate::Options::annotate`] the origins are printed as comments `// path:line: command`, but
+//! never read back from them.
+
+use std::fmt::Write;
+
+use crate::{
+    asm::{Asm, Line},
+    assembler::Instruction,
+    error::SourceSpan,
+};
+
+/// The VM command an instruction was translated from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    pub path: String,
+    /// 1-origin line number
+    pub line: usize,
+    /// VM text of the command as written, without comments
+    pub command: String,
+}
+
+impl Origin {
+    /// Origin of the command at `span`. Commands combined by `-O2` get the span of the first one.
+    pub fn from_span(span: &SourceSpan) -> Origin {
+        let code = span.source_line.split("//").next().unwrap_or_default();
+        Origin {
+            path: span.path.clone(),
+            line: span.line,
+            command: code.split_whitespace().collect::<Vec<_>>().join(" "),
+        }
+    }
+
+    /// Comment marking the start of the command in assembly.
+    pub fn marker(&self) -> String {
+        format!("// {}:{}: {}", self.path, self.line, self.command)
+    }
+}
+
+/// Prints `asm` with the number of ROM instructions of each command after its origin.
+pub fn annotate_counts(asm: &Asm) -> String {
+    let lines = asm.lines();
+    let mut annotated = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        let _ = write!(annotated, "{}", line);
+        if let Line::Origin(_) = line {
+            let count = lines[i + 1..]
+                .iter()
+                .take_while(|line| !matches!(line, Line::Origin(_)))
+                .filter(|line| is_rom_instruction(line))
+                .count();
+            let plural = if count == 1 { "" } else { "s" };
+            let _ = write!(annotated, " ({} instruction{})", count, plural);
+        }
+        annotated.push('\n');
+    }
+    annotated
+}
+
+fn is_rom_instruction(line: &Line) -> bool {
+    matches!(line, Line::Code(instruction) if !matches!(instruction, Instruction::Label(_)))
+}
+
+/// Origin of each instruction in ROM.
+#[derive(Debug)]
+pub struct SourceMap {
+    /// asm line (1-origin) and origin by ROM address, no origin for the bootstrap code
+    entries: Vec<(usize, Option<Origin>)>,
+}
+
+impl SourceMap {
+    /// Reads the origins of translated assembly. Lines are numbered as `asm` prints, with the
+    /// [`Line::Origin`] lines only if `annotated`.
+    pub fn from_asm(asm: &Asm, annotated: bool) -> SourceMap {
+        let mut entries = vec![];
+        let mut origin = None;
+        let mut asm_line = 0;
+        for line in asm.lines() {
+            match line {
+                Line::Origin(marked) => {
+                    origin = Some(marked.clone());
+                    if annotated {
+                        asm_line += 1;
+                    }
+                }
+                line => {
+                    asm_line += 1;
+                    if is_rom_instruction(line) {
+                        entries.push((asm_line, origin.clone()));
+                    }
+                }
+            }
+        }
+        SourceMap { entries }
+    }
+
+    /// Origin of the instruction at ROM `address`.
+    pub fn lookup(&self, address: usize) -> Option<&Origin> {
+        self.entries.get(address)?.1.as_ref()
+    }
+
+    /// Number of instructions in ROM.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// JSON sidecar with one entry per ROM address, `null` where no VM command is known.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\n  \"version\": 1,\n  \"instructions\": [");
+        for (address, (asm_line, origin)) in self.entries.iter().enumerate() {
+            if address > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "\n    {{\"address\": {}, \"asm_line\": {}, ",
+                address, asm_line
+            );
+            match origin {
+                Some(origin) => {
+                    let _ = write!(
+                        json,
+                        "\"file\": {}, \"line\": {}, \"command\": {}}}",
+                        json_string(&origin.path),
+                        origin.line,
+                        json_string(&origin.command)
+                    );
+                }
+                None => json.push_str("\"file\": null, \"line\": null, \"command\": null}"),
+            }
+        }
+        json.push_str("\n  ]\n}\n");
+        json
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{annotate_counts, Origin, SourceMap};
+    use crate::{
+        asm::Asm,
+        assembler::{Comp, Instruction, Jump},
+        translate_asm, Options,
+    };
+
+    #[test]
+    fn marker_test() {
+        let origin = Origin {
+            path: "dir/Main.vm".to_string(),
+            line: 12,
+            command: "push constant 7".to_string(),
+        };
+        assert_eq!("// dir/Main.vm:12: push constant 7", origin.marker());
+        assert_eq!(
+            "// dir/Main.vm:12: push constant 7\n",
+            Asm::new().origin(origin).to_string()
+        );
+    }
+
+    #[test]
+    fn counts_test() {
+        let origin = |line, command: &str| Origin {
+            path: "Main.vm".to_string(),
+            line,
+            command: command.to_string(),
+        };
+        let asm = Asm::new()
+            .comment("// SP = 256")
+            .at(256u16)
+            .origin(origin(1, "label L"))
+            .label("L")
+            .origin(origin(2, "goto L"))
+            .at("L")
+            // a comment which looks like a marker is not one
+            .comment("// Main.vm:3: goto L")
+            .jump(Comp::Zero, Jump::JMP);
+        assert_eq!(
+            "// SP = 256\n@256\n// Main.vm:1: label L (0 instructions)\n(L)\n// Main.vm:2: goto L (2 instructions)\n@L\n// Main.vm:3: goto L\n0;JMP\n",
+            annotate_counts(&asm)
+        );
+    }
+
+    #[test]
+    fn source_map_test() {
+        let source =
+            "function Main.main 0\n  push constant 7 // seven\npush constant 8\nadd\nreturn";
+        for opt_level in 0..=3 {
+            let options = Options {
+                entry: Some("Main.main".to_string()),
+                opt_level,
+                ..Default::default()
+            };
+            let asm = translate_asm(&[("Main.vm", source)], &options).unwrap();
+            let map = SourceMap::from_asm(&asm, true);
+            assert_eq!(
+                crate::assembler::assemble(&asm.to_string()).unwrap().len(),
+                map.len()
+            );
+            // the bootstrap comes first
+            assert_eq!(None, map.lookup(0));
+
+            let origins: Vec<&Origin> = (0..map.len()).filter_map(|a| map.lookup(a)).collect();
+            assert_eq!("Main.vm", origins[0].path);
+            assert!(origins.iter().any(|o| o.line == 5 && o.command == "return"));
+            // folding attributes the sum to the first push
+            let push = origins.iter().find(|o| o.line == 2).unwrap();
+            assert_eq!("push constant 7", push.command);
+            if opt_level >= 2 {
+                assert!(!origins.iter().any(|o| o.line == 4));
+            }
+        }
+    }
+
+    #[test]
+    fn asm_line_test() {
+        let options = Options {
+            debug: true,
+            ..Default::default()
+        };
+        let asm = translate_asm(&[("Main.vm", "push constant 7\npop local 0")], &options).unwrap();
+        let stripped = asm.clone().without_comments();
+        // the map follows the text as it is printed
+        for (asm, annotated, text) in [
+            (&asm, true, asm.to_string()),
+            (&asm, false, asm.clone().without_origins().to_string()),
+            (
+                &stripped,
+                false,
+                stripped.clone().without_origins().to_string(),
+            ),
+        ] {
+            let map = SourceMap::from_asm(asm, annotated);
+            let lines: Vec<&str> = text.lines().collect();
+            let rom: Vec<String> = asm
+                .instructions()
+                .filter(|i| !matches!(i, Instruction::Label(_)))
+                .map(|i| i.to_string())
+                .collect();
+            assert_eq!(rom.len(), map.len());
+            for (address, (asm_line, _)) in map.entries.iter().enumerate() {
+                assert_eq!(rom[address], lines[asm_line - 1]);
+            }
+            assert_eq!(Some(2), map.lookup(map.len() - 1).map(|o| o.line));
+        }
+    }
+
+    #[test]
+    fn json_test() {
+        let options = Options {
+            debug: true,
+            ..Default::default()
+        };
+        let asm = translate_asm(&[("Main.vm", "push constant 7")], &options).unwrap();
+        let json = SourceMap::from_asm(&asm, true).to_json();
+        assert!(json.starts_with("{\n  \"version\": 1,\n  \"instructions\": [\n    {\"address\": 0, \"asm_line\": 3, \"file\": null, \"line\": null, \"command\": null},"));
+        assert!(
+            json.contains("\"file\": \"Main.vm\", \"line\": 1, \"command\": \"push constant 7\"}")
+        );
+        assert!(json.ends_with("}\n  ]\n}\n"));
+        assert_eq!("\"a\\\"b\\\\\\u000a\"", super::json_string("a\"b\\\n"));
+    }
+}
@@ -1,254 +1,457 @@
-pub static ADD_ASM: &str = r"
-// pop to save register (AM=M-1, M=D+M)
-@SP
-AM=M-1
-D=M
-// pop
-@SP
-AM=M-1
-// add
-M=M+D
-@SP
-M=M+1
-";
-
-pub static SUB_ASM: &str = r"
-// pop to save register (AM=M-1, M=D+M)
-@SP
-AM=M-1
-D=M
-// pop
-@SP
-AM=M-1
-// add
-M=M-D
-@SP
-M=M+1
-";
-
-pub static NEG_ASM: &str = r"
-@SP
-AM=M-1
-// neg
-M=-M
-@SP
-M=M+1
-";
-
-pub static AND_ASM: &str = r"
-// and
-// pop to save register (AM=M-1, M=D+M)
-@SP
-AM=M-1
-D=M
-// pop
-@SP
-AM=M-1
-// and
-M=M&D
-@SP
-M=M+1
-";
-
-pub static OR_ASM: &str = r"
-// and
-// pop to save register (AM=M-1, M=D+M)
-@SP
-AM=M-1
-D=M
-// pop
-@SP
-AM=M-1
-// or
-M=M|D
-@SP
-M=M+1
-";
-
-pub static NOT_ASM: &str = r"
-@SP
-AM=M-1
-// not 
-M=!M
-@SP
-M=M+1
-";
-
-pub static PUSH_CONST_AMS: &str = "
-@{}
-D=A
-@SP
-A=M
-M=D
-@SP
-M=M+1
-";
-
-pub static PUSH_STATIC_AMS: &str = "
-@{}
-D=M
-@SP
-A=M
-M=D
-@SP
-M=M+1
-";
-
-pub static POP_STATIC_AMS: &str = "
-@SP
-AM=M-1
-D=M
-@{}
-M=D
-";
-
-pub static CMP_CONST_ASM: &str = "
-@SP
-AM=M-1
-D=M
-@SP
-AM=M-1
-
-D=M-D
-@RETURNTRUE
-D;TRUECMPLABEL
-@RETURNFALSE
-D;FALSECMPLABEL
-
-(RETURNTRUE)
-D=-1
-@RETURNEND
-0;JMP
-(RETURNFALSE)
-D=0
-(RETURNEND)
-@SP
-A=M
-M=D
-@SP
-M=M+1
-";
-
-pub static RET_TRUE_LABEL: &str = "RETURNTRUE";
-pub static RET_FALSE_LABEL: &str = "RETURNFALSE";
-pub static RET_END_LABEL: &str = "RETURNEND";
-pub static TRUE_CMP_LABEL: &str = "TRUECMPLABEL";
-pub static FALSE_CMP_LABEL: &str = "FALSECMPLABEL";
-
-pub fn generate_pop_specified_register_template(index: usize, reg_name: &str) -> String {
-    format!(
-        r###"
-@{index}
-D=A
-@{reg_name}
-D=M+D
-@R13
-M=D
-
-@SP
-AM=M-1
-D=M
-@R13
-A=M
-M=D
-
-"###,
-    )
-}
-
-pub fn generate_push_specified_register_template(index: usize, reg_name: &str) -> String {
-    format!(
-        r###"
-@{index}
-D=A
-@{reg_name}
-D=M+D
-@R13
-M=D
-
-@R13
-A=M
-D=M
-@SP
-A=M
-M=D
-@SP
-M=M+1
-
-"###,
-    )
-}
-
-pub fn generate_pop_specified_register_template_pointer(index: usize, reg_name: &str) -> String {
-    format!(
-        r###"
-@{index}
-D=A
-@{reg_name}
-D=A+D
-@R13
-M=D
-
-@SP
-AM=M-1
-D=M
-@R13
-A=M
-M=D
-"###,
-    )
-}
-pub fn generate_push_specified_register_template_pointer(index: usize, reg_name: &str) -> String {
-    format!(
-        r###"
-@{index}
-D=A
-@{reg_name}
-D=A+D
-@R13
-M=D
-
-@R13
-A=M
-D=M
-@SP
-A=M
-M=D
-@SP
-M=M+1
-"###,
-    )
-}
-
-pub static INIT: &str = "
-// replace to 256
-@256
-D=A
-@SP
-M=D
-
-// set to ARG reg
-@400
-D=A
-@ARG
-M=D
-";
-
-pub static DEF_LABEL_AMS: &str = "
-(LABEL_NAME)
-";
-
-pub static GOTO_LABEL_AMS: &str = "
-@LABEL_NAME
-0;JMP
-";
-
-pub static IFGOTO_LABEL_AMS: &str = "
-@SP
-AM=M-1
-D=M
-@LABEL_NAME
-D;JNE
-";
-
-pub static LABEL_NAME: &str = "LABEL_NAME";
+use crate::{
+    asm::Asm,
+    assembler::{AValue, Comp, Dest, Jump},
+    command::ArithOp,
+};
+
+/// `op` of x and y, `comp` combines y in D with x in M: add, sub, and, or.
+pub fn generate_binary_template(op: ArithOp, comp: Comp) -> Asm {
+    Asm::new()
+        .blank()
+        .comment("// pop y into D")
+        .at("SP")
+        .assign(Dest::AM, Comp::MMinusOne)
+        .assign(Dest::D, Comp::M)
+        .comment("// pop x, A points to it")
+        .at("SP")
+        .assign(Dest::AM, Comp::MMinusOne)
+        .comment(&format!("// {}", op))
+        .assign(Dest::M, comp)
+        .at("SP")
+        .assign(Dest::M, Comp::MPlusOne)
+}
+
+/// `op` of x, `comp` computes it from x in M: neg, not.
+pub fn generate_unary_template(op: ArithOp, comp: Comp) -> Asm {
+    Asm::new()
+        .blank()
+        .at("SP")
+        .assign(Dest::AM, Comp::MMinusOne)
+        .comment(&format!("// {}", op))
+        .assign(Dest::M, comp)
+        .at("SP")
+        .assign(Dest::M, Comp::MPlusOne)
+}
+
+/// push constant `value`, for values @ can load
+pub fn generate_push_constant_template(value: u16) -> Asm {
+    Asm::new().blank().at(value).assign(Dest::D, Comp::A) + generate_push_d_template()
+}
+
+pub fn generate_push_static_template(symbol: &str) -> Asm {
+    Asm::new().blank().at(symbol).assign(Dest::D, Comp::M) + generate_push_d_template()
+}
+
+pub fn generate_pop_static_template(symbol: &str) -> Asm {
+    generate_pop_d_template()
+        .at(symbol)
+        .assign(Dest::M, Comp::D)
+}
+
+/// D = pop()
+pub fn generate_pop_d_template() -> Asm {
+    Asm::new()
+        .blank()
+        .at("SP")
+        .assign(Dest::AM, Comp::MMinusOne)
+        .assign(Dest::D, Comp::M)
+}
+
+/// Labels of one comparison, made unique by the running count `n`.
+///
+/// They begin with `$$`, which no mangled VM label can, see [`crate::parser`].
+pub struct CmpLabels {
+    x_negative: String,
+    same_sign: String,
+    result: String,
+    ret_true: String,
+    ret_false: String,
+    ret_end: String,
+}
+
+impl CmpLabels {
+    pub fn new(op: ArithOp, n: usize) -> CmpLabels {
+        let label = |name| format!("$${}.{}.{}", name, op, n);
+        CmpLabels {
+            x_negative: label("CMPXNEGATIVE"),
+            same_sign: label("CMPSAMESIGN"),
+            result: label("CMPRESULT"),
+            ret_true: label("RETURNTRUE"),
+            ret_false: label("RETURNFALSE"),
+            ret_end: label("RETURNEND"),
+        }
+    }
+}
+
+/// Pops x and leaves the sign of x - y in D, with y in D after [`generate_pop_d_template`].
+pub fn generate_cmp_diff_template(labels: &CmpLabels) -> Asm {
+    Asm::new()
+        .at("R13")
+        .assign(Dest::M, Comp::D)
+        .at("SP")
+        .assign(Dest::AM, Comp::MMinusOne)
+        .assign(Dest::D, Comp::M)
+        .blank()
+        .comment("// x - y overflows when the signs differ, then the sign of x decides")
+        .at(labels.x_negative.as_str())
+        .jump(Comp::D, Jump::JLT)
+        .at("R13")
+        .assign(Dest::D, Comp::M)
+        .at(labels.same_sign.as_str())
+        .jump(Comp::D, Jump::JGE)
+        .assign(Dest::D, Comp::One)
+        .at(labels.result.as_str())
+        .jump(Comp::Zero, Jump::JMP)
+        .label(&labels.x_negative)
+        .at("R13")
+        .assign(Dest::D, Comp::M)
+        .at(labels.same_sign.as_str())
+        .jump(Comp::D, Jump::JLT)
+        .assign(Dest::D, Comp::MinusOne)
+        .at(labels.result.as_str())
+        .jump(Comp::Zero, Jump::JMP)
+        .label(&labels.same_sign)
+        .at("SP")
+        .assign(Dest::A, Comp::M)
+        .assign(Dest::D, Comp::M)
+        .at("R13")
+        .assign(Dest::D, Comp::DMinusM)
+        .label(&labels.result)
+}
+
+/// Pops x and leaves x - y in D, enough to decide `eq`, with y in D after
+/// [`generate_pop_d_template`].
+pub fn generate_eq_diff_template() -> Asm {
+    Asm::new()
+        .at("SP")
+        .assign(Dest::AM, Comp::MMinusOne)
+        .assign(Dest::D, Comp::MMinusD)
+}
+
+/// Pushes -1 or 0 after [`generate_cmp_diff_template`], jumping on the conditions of true and
+/// false.
+pub fn generate_cmp_push_template(labels: &CmpLabels, true_jump: Jump, false_jump: Jump) -> Asm {
+    Asm::new()
+        .at(labels.ret_true.as_str())
+        .jump(Comp::D, true_jump)
+        .at(labels.ret_false.as_str())
+        .jump(Comp::D, false_jump)
+        .blank()
+        .label(&labels.ret_true)
+        .assign(Dest::D, Comp::MinusOne)
+        .at(labels.ret_end.as_str())
+        .jump(Comp::Zero, Jump::JMP)
+        .label(&labels.ret_false)
+        .assign(Dest::D, Comp::Zero)
+        .label(&labels.ret_end)
+        + generate_push_d_template()
+}
+
+/// D = -1 or 0 after [`generate_cmp_diff_template`] or [`generate_eq_diff_template`].
+pub fn generate_cmp_bool_d_template(labels: &CmpLabels, true_jump: Jump) -> Asm {
+    Asm::new()
+        .at(labels.ret_true.as_str())
+        .jump(Comp::D, true_jump)
+        .assign(Dest::D, Comp::Zero)
+        .at(labels.ret_end.as_str())
+        .jump(Comp::Zero, Jump::JMP)
+        .label(&labels.ret_true)
+        .assign(Dest::D, Comp::MinusOne)
+        .label(&labels.ret_end)
+}
+
+/// Jumps to `label` after [`generate_cmp_diff_template`] or [`generate_eq_diff_template`] when
+/// the comparison holds.
+pub fn generate_cmp_jump_template(label: &str, true_jump: Jump) -> Asm {
+    Asm::new().at(label).jump(Comp::D, true_jump)
+}
+
+/// Return address of a shared comparison subroutine, which jumps to R14 when done.
+pub fn generate_set_return_template(return_label: &str) -> Asm {
+    Asm::new()
+        .blank()
+        .at(return_label)
+        .assign(Dest::D, Comp::A)
+        .at("R14")
+        .assign(Dest::M, Comp::D)
+}
+
+pub fn generate_return_to_r14_template() -> Asm {
+    Asm::new()
+        .at("R14")
+        .assign(Dest::A, Comp::M)
+        .jump(Comp::Zero, Jump::JMP)
+}
+
+/// R13 = `register` + `index`, the address of the entry in a segment based at RAM[register]
+fn generate_segment_address_template(index: u16, register: AValue) -> Asm {
+    Asm::new()
+        .blank()
+        .at(index)
+        .assign(Dest::D, Comp::A)
+        .at(register)
+        .assign(Dest::D, Comp::MPlusD)
+        .at("R13")
+        .assign(Dest::M, Comp::D)
+        .blank()
+}
+
+/// R13 = `address` + `index`, the address of the entry in a segment at a fixed address
+fn generate_fixed_address_template(index: u16, address: AValue) -> Asm {
+    Asm::new()
+        .blank()
+        .at(index)
+        .assign(Dest::D, Comp::A)
+        .at(address)
+        .assign(Dest::D, Comp::APlusD)
+        .at("R13")
+        .assign(Dest::M, Comp::D)
+        .blank()
+}
+
+/// RAM[R13] = pop()
+fn generate_pop_to_r13_template() -> Asm {
+    Asm::new()
+        .at("SP")
+        .assign(Dest::AM, Comp::MMinusOne)
+        .assign(Dest::D, Comp::M)
+        .at("R13")
+        .assign(Dest::A, Comp::M)
+        .assign(Dest::M, Comp::D)
+}
+
+/// push(RAM[R13])
+fn generate_push_from_r13_template() -> Asm {
+    Asm::new()
+        .at("R13")
+        .assign(Dest::A, Comp::M)
+        .assign(Dest::D, Comp::M)
+        + generate_push_d_template()
+}
+
+pub fn generate_pop_specified_register_template(index: u16, register: AValue) -> Asm {
+    generate_segment_address_template(index, register) + generate_pop_to_r13_template().blank()
+}
+
+pub fn generate_push_specified_register_template(index: u16, register: AValue) -> Asm {
+    generate_segment_address_template(index, register) + generate_push_from_r13_template().blank()
+}
+
+pub fn generate_pop_specified_register_template_pointer(index: u16, address: AValue) -> Asm {
+    generate_fixed_address_template(index, address) + generate_pop_to_r13_template()
+}
+
+pub fn generate_push_specified_register_template_pointer(index: u16, address: AValue) -> Asm {
+    generate_fixed_address_template(index, address) + generate_push_from_r13_template()
+}
+
+/// D = value, for any 16-bit value
+pub fn generate_load_value_template(value: u16) -> Asm {
+    let asm = Asm::new().blank();
+    match value {
+        0 => asm.assign(Dest::D, Comp::Zero),
+        1 => asm.assign(Dest::D, Comp::One),
+        0xffff => asm.assign(Dest::D, Comp::MinusOne),
+        // @ only loads 15 bits
+        0x8000.. => asm.at(!value).assign(Dest::D, Comp::NotA),
+        _ => asm.at(value).assign(Dest::D, Comp::A),
+    }
+}
+
+/// push(D)
+pub fn generate_push_d_template() -> Asm {
+    Asm::new()
+        .at("SP")
+        .assign(Dest::A, Comp::M)
+        .assign(Dest::M, Comp::D)
+        .at("SP")
+        .assign(Dest::M, Comp::MPlusOne)
+}
+
+/// Stack top = `comp`, SP is left as it is.
+pub fn generate_stack_top_template(comp: Comp) -> Asm {
+    Asm::new()
+        .blank()
+        .at("SP")
+        .assign(Dest::A, Comp::MMinusOne)
+        .assign(Dest::M, comp)
+}
+
+pub fn generate_init_template() -> Asm {
+    Asm::new()
+        .blank()
+        .comment("// SP = 256")
+        .at(256u16)
+        .assign(Dest::D, Comp::A)
+        .at("SP")
+        .assign(Dest::M, Comp::D)
+}
+
+pub fn generate_label_template(label: &str) -> Asm {
+    Asm::new().blank().label(label)
+}
+
+pub fn generate_goto_template(label: &str) -> Asm {
+    Asm::new().blank().at(label).jump(Comp::Zero, Jump::JMP)
+}
+
+pub fn generate_if_goto_template(label: &str) -> Asm {
+    generate_pop_d_template().at(label).jump(Comp::D, Jump::JNE)
+}
+
+/// if-goto on the value in D
+pub fn generate_if_d_goto_template(label: &str) -> Asm {
+    Asm::new().blank().at(label).jump(Comp::D, Jump::JNE)
+}
+
+/// if-goto on a false value
+pub fn generate_if_not_goto_template(label: &str) -> Asm {
+    generate_pop_d_template().at(label).jump(Comp::D, Jump::JEQ)
+}
+
+pub fn generate_function_template(function_name: &str, n_vars: usize) -> Asm {
+    let mut asm = Asm::new().blank().label(function_name);
+    for _ in 0..n_vars {
+        asm = asm
+            .at("SP")
+            .assign(Dest::A, Comp::M)
+            .assign(Dest::M, Comp::Zero)
+            .at("SP")
+            .assign(Dest::M, Comp::MPlusOne);
+    }
+    asm
+}
+
+/// Pushes LCL, ARG, THIS and THAT after the return address.
+fn generate_save_frame_template() -> Asm {
+    let mut asm = Asm::new();
+    for register in ["LCL", "ARG", "THIS", "THAT"] {
+        asm = asm
+            .comment(&format!("// push {}", register))
+            .at(register)
+            .assign(Dest::D, Comp::M)
+            + generate_push_d_template();
+    }
+    asm
+}
+
+pub fn generate_call_template(function_name: &str, n_args: u16, return_label: &str) -> Asm {
+    Asm::new()
+        .blank()
+        .comment("// push return address")
+        .at(return_label)
+        .assign(Dest::D, Comp::A)
+        + generate_push_d_template()
+        + generate_save_frame_template()
+            .comment("// ARG = SP - 5 - nArgs")
+            .at("SP")
+            .assign(Dest::D, Comp::M)
+            .at(n_args + 5)
+            .assign(Dest::D, Comp::DMinusA)
+            .at("ARG")
+            .assign(Dest::M, Comp::D)
+            .comment("// LCL = SP")
+            .at("SP")
+            .assign(Dest::D, Comp::M)
+            .at("LCL")
+            .assign(Dest::M, Comp::D)
+            .comment("// goto function")
+            .at(function_name)
+            .jump(Comp::Zero, Jump::JMP)
+            .label(return_label)
+}
+
+pub static SHARED_CALL_LABEL: &str = "$$CALL";
+pub static SHARED_RETURN_LABEL: &str = "$$RETURN";
+
+/// Arguments of `$$CALL`: R13 = function, R14 = nArgs, R15 = return address.
+pub fn generate_shared_call_args_template(
+    function_name: &str,
+    n_args: u16,
+    return_label: &str,
+) -> Asm {
+    Asm::new()
+        .blank()
+        .at(function_name)
+        .assign(Dest::D, Comp::A)
+        .at("R13")
+        .assign(Dest::M, Comp::D)
+        .at(n_args)
+        .assign(Dest::D, Comp::A)
+        .at("R14")
+        .assign(Dest::M, Comp::D)
+        .at(return_label)
+        .assign(Dest::D, Comp::A)
+        .at("R15")
+        .assign(Dest::M, Comp::D)
+}
+
+/// Body of `$$CALL`, the frame saved by [`generate_call_template`].
+pub fn generate_shared_call_template() -> Asm {
+    Asm::new()
+        .blank()
+        .comment("// push return address")
+        .at("R15")
+        .assign(Dest::D, Comp::M)
+        + generate_push_d_template()
+        + generate_save_frame_template()
+            .comment("// ARG = SP - 5 - nArgs")
+            .at("SP")
+            .assign(Dest::D, Comp::M)
+            .at(5u16)
+            .assign(Dest::D, Comp::DMinusA)
+            .at("R14")
+            .assign(Dest::D, Comp::DMinusM)
+            .at("ARG")
+            .assign(Dest::M, Comp::D)
+            .comment("// LCL = SP")
+            .at("SP")
+            .assign(Dest::D, Comp::M)
+            .at("LCL")
+            .assign(Dest::M, Comp::D)
+            .comment("// goto function")
+            .at("R13")
+            .assign(Dest::A, Comp::M)
+            .jump(Comp::Zero, Jump::JMP)
+}
+
+pub fn generate_return_template() -> Asm {
+    let mut asm = Asm::new()
+        .blank()
+        .comment("// FRAME = LCL")
+        .at("LCL")
+        .assign(Dest::D, Comp::M)
+        .at("R13")
+        .assign(Dest::M, Comp::D)
+        .comment("// RET = *(FRAME - 5)")
+        .at(5u16)
+        .assign(Dest::A, Comp::DMinusA)
+        .assign(Dest::D, Comp::M)
+        .at("R14")
+        .assign(Dest::M, Comp::D)
+        .comment("// *ARG = pop()")
+        .at("SP")
+        .assign(Dest::AM, Comp::MMinusOne)
+        .assign(Dest::D, Comp::M)
+        .at("ARG")
+        .assign(Dest::A, Comp::M)
+        .assign(Dest::M, Comp::D)
+        .comment("// SP = ARG + 1")
+        .at("ARG")
+        .assign(Dest::D, Comp::MPlusOne)
+        .at("SP")
+        .assign(Dest::M, Comp::D);
+    for (i, register) in ["THAT", "THIS", "ARG", "LCL"].into_iter().enumerate() {
+        asm = asm
+            .comment(&format!("// {} = *(FRAME - {})", register, i + 1))
+            .at("R13")
+            .assign(Dest::AM, Comp::MMinusOne)
+            .assign(Dest::D, Comp::M)
+            .at(register)
+            .assign(Dest::M, Comp::D);
+    }
+    asm.comment("// goto RET")
+        .at("R14")
+        .assign(Dest::A, Comp::M)
+        .jump(Comp::Zero, Jump::JMP)
+}
@@ -0,0 +1,582 @@
+//! Interpreter of the nand2tetris test script language (`.tst`) for the CPU emulator.
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::emulator::Emulator;
+
+#[derive(Debug)]
+pub enum TstError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Syntax {
+        line: usize,
+        message: String,
+    },
+    Load {
+        path: PathBuf,
+        message: String,
+    },
+}
+
+impl fmt::Display for TstError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TstError::Io { path, source } => {
+                write!(f, "cannot read `{}`: {}", path.display(), source)
+            }
+            TstError::Syntax { line, message } => write!(f, "line {}: {}", line, message),
+            TstError::Load { path, message } => {
+                write!(f, "cannot load `{}`: {}", path.display(), message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TstError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Radix {
+    Decimal,
+    Hex,
+    Binary,
+    String,
+}
+
+/// One column of `output-list`, e.g. `RAM[256]%D1.6.1`.
+#[derive(Debug, Clone, PartialEq)]
+struct OutputColumn {
+    name: String,
+    radix: Radix,
+    pad_left: usize,
+    len: usize,
+    pad_right: usize,
+}
+
+#[derive(Debug)]
+enum Statement {
+    Command { line: usize, words: Vec<String> },
+    Repeat { count: usize, body: Vec<Statement> },
+}
+
+/// Result of a test script run.
+#[derive(Debug)]
+pub struct TestReport {
+    /// table written to the output file
+    pub output: String,
+    pub output_file: Option<PathBuf>,
+    pub compare_file: Option<PathBuf>,
+    /// first line differing from the compare file, 1-origin
+    pub mismatch: Option<Mismatch>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        self.mismatch.is_none()
+    }
+}
+
+/// Splits the script into words and terminators (`,` `;` `!` `{` `}`), dropping comments.
+fn tokenize(script: &str) -> Vec<(usize, String)> {
+    let mut tokens = vec![];
+    let mut in_block_comment = false;
+    for (i, l) in script.lines().enumerate() {
+        let mut rest = l;
+        let mut word = String::new();
+        let flush = |word: &mut String, tokens: &mut Vec<(usize, String)>| {
+            if !word.is_empty() {
+                tokens.push((i + 1, std::mem::take(word)));
+            }
+        };
+        while !rest.is_empty() {
+            if in_block_comment {
+                match rest.find("*/") {
+                    Some(pos) => {
+                        rest = &rest[pos + 2..];
+                        in_block_comment = false;
+                    }
+                    None => rest = "",
+                }
+                continue;
+            }
+            if rest.starts_with("//") {
+                break;
+            }
+            if rest.starts_with("/*") {
+                flush(&mut word, &mut tokens);
+                in_block_comment = true;
+                rest = &rest[2..];
+                continue;
+            }
+            let c = rest.chars().next().unwrap();
+            if c == '"' {
+                // quoted string of echo
+                let end = rest[1..].find('"').map(|p| p + 1).unwrap_or(rest.len() - 1);
+                word.push_str(&rest[1..end]);
+                rest = &rest[(end + 1).min(rest.len())..];
+                continue;
+            }
+            if c.is_whitespace() {
+                flush(&mut word, &mut tokens);
+            } else if ",;!{}".contains(c) {
+                flush(&mut word, &mut tokens);
+                tokens.push((i + 1, c.to_string()));
+            } else {
+                word.push(c);
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+        flush(&mut word, &mut tokens);
+    }
+    tokens
+}
+
+fn parse_statements(
+    tokens: &[(usize, String)],
+    pos: &mut usize,
+    in_block: bool,
+) -> Result<Vec<Statement>, TstError> {
+    let mut statements = vec![];
+    let mut words: Vec<String> = vec![];
+    let mut line = 0;
+    while *pos < tokens.len() {
+        let (l, token) = &tokens[*pos];
+        *pos += 1;
+        match token.as_str() {
+            "," | ";" | "!" => {
+                if !words.is_empty() {
+                    statements.push(Statement::Command {
+                        line,
+                        words: std::mem::take(&mut words),
+                    });
+                }
+            }
+            "{" => {
+                if words.first().map(|w| w.as_str()) != Some("repeat") || words.len() > 2 {
+                    return Err(TstError::Syntax {
+                        line: *l,
+                        message: format!("unsupported block `{}`", words.join(" ")),
+                    });
+                }
+                let count = match words.get(1) {
+                    Some(n) => n.parse().map_err(|_| TstError::Syntax {
+                        line: *l,
+                        message: format!("invalid repeat count `{}`", n),
+                    })?,
+                    None => {
+                        return Err(TstError::Syntax {
+                            line: *l,
+                            message: "repeat without a count never ends".to_string(),
+                        })
+                    }
+                };
+                words.clear();
+                let body = parse_statements(tokens, pos, true)?;
+                statements.push(Statement::Repeat { count, body });
+            }
+            "}" => {
+                if !in_block {
+                    return Err(TstError::Syntax {
+                        line: *l,
+                        message: "unexpected `}`".to_string(),
+                    });
+                }
+                if !words.is_empty() {
+                    statements.push(Statement::Command { line, words });
+                }
+                return Ok(statements);
+            }
+            word => {
+                if words.is_empty() {
+                    line = *l;
+                }
+                words.push(word.to_string());
+            }
+        }
+    }
+    if in_block {
+        return Err(TstError::Syntax {
+            line: tokens.last().map(|t| t.0).unwrap_or(0),
+            message: "missing `}`".to_string(),
+        });
+    }
+    if !words.is_empty() {
+        statements.push(Statement::Command { line, words });
+    }
+    Ok(statements)
+}
+
+fn parse_column(line: usize, item: &str) -> Result<OutputColumn, TstError> {
+    let error = || TstError::Syntax {
+        line,
+        message: format!("invalid output-list item `{}`", item),
+    };
+    let (name, format) = item.split_once('%').unwrap_or((item, "D1.6.1"));
+    let radix = match format.chars().next() {
+        Some('D') => Radix::Decimal,
+        Some('X') => Radix::Hex,
+        Some('B') => Radix::Binary,
+        Some('S') => Radix::String,
+        _ => return Err(error()),
+    };
+    let widths: Vec<usize> = format[1..]
+        .split('.')
+        .map(|w| w.parse().map_err(|_| error()))
+        .collect::<Result<_, _>>()?;
+    if widths.len() != 3 {
+        return Err(error());
+    }
+    Ok(OutputColumn {
+        name: name.to_string(),
+        radix,
+        pad_left: widths[0],
+        len: widths[1],
+        pad_right: widths[2],
+    })
+}
+
+/// Parses `%D-1`, `%X7FFF`, `%B101` or plain decimal values.
+fn parse_value(line: usize, value: &str) -> Result<u16, TstError> {
+    let error = || TstError::Syntax {
+        line,
+        message: format!("invalid value `{}`", value),
+    };
+    let parsed = match value.strip_prefix('%') {
+        Some(v) if v.starts_with('X') => i32::from_str_radix(&v[1..], 16),
+        Some(v) if v.starts_with('B') => i32::from_str_radix(&v[1..], 2),
+        Some(v) if v.starts_with('D') => v[1..].parse(),
+        Some(_) => return Err(error()),
+        None => value.parse(),
+    };
+    match parsed {
+        Ok(v) if (-32768..=65535).contains(&v) => Ok(v as u16),
+        _ => Err(error()),
+    }
+}
+
+struct Runner<'a> {
+    dir: &'a Path,
+    emulator: Emulator,
+    columns: Vec<OutputColumn>,
+    output: String,
+    output_file: Option<PathBuf>,
+    compare_file: Option<PathBuf>,
+}
+
+impl Runner<'_> {
+    fn read(&self, line: usize, emulator_value: &str) -> Result<u16, TstError> {
+        let error = || TstError::Syntax {
+            line,
+            message: format!("unknown variable `{}`", emulator_value),
+        };
+        match emulator_value {
+            "A" => Ok(self.emulator.a()),
+            "D" => Ok(self.emulator.d()),
+            "PC" => Ok(self.emulator.pc()),
+            _ => {
+                let address = ram_address(emulator_value).ok_or_else(error)?;
+                Ok(self.emulator.ram()[address])
+            }
+        }
+    }
+
+    fn header(&self) -> String {
+        let mut header = String::from("|");
+        for c in &self.columns {
+            let width = c.pad_left + c.len + c.pad_right;
+            let name: String = c.name.chars().take(width).collect();
+            let left = (width - name.len()) / 2;
+            let right = width - name.len() - left;
+            header.push_str(&format!(
+                "{}{}{}|",
+                " ".repeat(left),
+                name,
+                " ".repeat(right)
+            ));
+        }
+        header
+    }
+
+    fn output_line(&self, line: usize) -> Result<String, TstError> {
+        let mut out = String::from("|");
+        for c in &self.columns {
+            let value = self.read(line, &c.name)?;
+            let mut text = match c.radix {
+                Radix::Decimal | Radix::String => (value as i16).to_string(),
+                Radix::Hex => format!("{:04X}", value),
+                Radix::Binary => format!("{:016b}", value),
+            };
+            if text.len() > c.len {
+                text = text[text.len() - c.len..].to_string();
+            }
+            // like the official tools, decimals keep the last column free when they fit
+            let trailing = match c.radix {
+                Radix::Decimal if text.len() < c.len => 1,
+                _ => 0,
+            };
+            out.push_str(&format!(
+                "{}{}{}|",
+                " ".repeat(c.pad_left + c.len - text.len() - trailing),
+                text,
+                " ".repeat(c.pad_right + trailing)
+            ));
+        }
+        Ok(out)
+    }
+
+    fn execute(&mut self, statements: &[Statement]) -> Result<(), TstError> {
+        for statement in statements {
+            match statement {
+                Statement::Repeat { count, body } => {
+                    for _ in 0..*count {
+                        self.execute(body)?;
+                    }
+                }
+                Statement::Command { line, words } => self.command(*line, words)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, line: usize, words: &[String]) -> Result<(), TstError> {
+        let arg = |n: usize| {
+            words
+                .get(n)
+                .map(|w| w.as_str())
+                .ok_or_else(|| TstError::Syntax {
+                    line,
+                    message: format!("`{}` needs more arguments", words[0]),
+                })
+        };
+        match words[0].as_str() {
+            "load" => {
+                let path = self.dir.join(arg(1)?);
+                let asm = fs::read_to_string(&path).map_err(|e| TstError::Io {
+                    path: path.clone(),
+                    source: e,
+                })?;
+                self.emulator = Emulator::from_asm(&asm).map_err(|e| TstError::Load {
+                    path,
+                    message: e.to_string(),
+                })?;
+            }
+            "output-file" => self.output_file = Some(self.dir.join(arg(1)?)),
+            "compare-to" => self.compare_file = Some(self.dir.join(arg(1)?)),
+            "output-list" => {
+                self.columns = words[1..]
+                    .iter()
+                    .map(|w| parse_column(line, w))
+                    .collect::<Result<_, _>>()?;
+                self.output.push_str(&self.header());
+                self.output.push('\n');
+            }
+            "output" => {
+                let out = self.output_line(line)?;
+                self.output.push_str(&out);
+                self.output.push('\n');
+            }
+            "set" => {
+                let value = parse_value(line, arg(2)?)?;
+                match arg(1)? {
+                    // only `set PC 0` is used by the official scripts
+                    "PC" if value == 0 => self.emulator.reset(),
+                    name => {
+                        let address = ram_address(name).ok_or_else(|| TstError::Syntax {
+                            line,
+                            message: format!("cannot set `{}`", name),
+                        })?;
+                        self.emulator.ram_mut()[address] = value;
+                    }
+                }
+            }
+            "ticktock" | "tock" => self.emulator.step(),
+            "tick" => {}
+            "echo" => eprintln!("{}", words[1..].join(" ")),
+            "clear-echo" => {}
+            other => {
+                return Err(TstError::Syntax {
+                    line,
+                    message: format!("unsupported command `{}`", other),
+                })
+            }
+        }
+        Ok(())
+    }
+}
+
+fn ram_address(name: &str) -> Option<usize> {
+    let address = name.strip_prefix("RAM[")?.strip_suffix(']')?.parse().ok()?;
+    (address < crate::emulator::RAM_SIZE).then_some(address)
+}
+
+/// Runs a test script whose `load`/`output-file`/`compare-to` paths are relative to `dir`.
+///
+/// The output file itself is not written.
+pub fn run_script(script: &str, dir: &Path) -> Result<TestReport, TstError> {
+    let tokens = tokenize(script);
+    let statements = parse_statements(&tokens, &mut 0, false)?;
+
+    let mut runner = Runner {
+        dir,
+        emulator: Emulator::new(&[]),
+        columns: vec![],
+        output: String::new(),
+        output_file: None,
+        compare_file: None,
+    };
+    runner.execute(&statements)?;
+
+    let mismatch = match &runner.compare_file {
+        Some(path) => {
+            let expected = fs::read_to_string(path).map_err(|e| TstError::Io {
+                path: path.clone(),
+                source: e,
+            })?;
+            compare(&expected, &runner.output)
+        }
+        None => None,
+    };
+    Ok(TestReport {
+        output: runner.output,
+        output_file: runner.output_file,
+        compare_file: runner.compare_file,
+        mismatch,
+    })
+}
+
+/// Runs a `.tst` file and writes its `.out` file next to it.
+pub fn run_file(path: &Path) -> Result<TestReport, TstError> {
+    let script = fs::read_to_string(path).map_err(|e| TstError::Io {
+        path: path.to_path_buf(),
+        source: e,
+    })?;
+    let report = run_script(&script, path.parent().unwrap_or_else(|| Path::new(".")))?;
+    if let Some(out) = &report.output_file {
+        fs::write(out, &report.output).map_err(|e| TstError::Io {
+            path: out.clone(),
+            source: e,
+        })?;
+    }
+    Ok(report)
+}
+
+fn compare(expected: &str, actual: &str) -> Option<Mismatch> {
+    let expected: Vec<&str> = expected.lines().map(|l| l.trim_end()).collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    for i in 0..expected.len().max(actual.len()) {
+        let e = expected.get(i).copied().unwrap_or("");
+        let a = actual.get(i).copied().unwrap_or("");
+        if e != a {
+            return Some(Mismatch {
+                line: i + 1,
+                expected: e.to_string(),
+                actual: a.to_string(),
+            });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::{run_script, Mismatch};
+
+    #[test]
+    fn format_test() {
+        let dir = std::env::temp_dir().join("vmtrans_tst_format_test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Prog.asm"), "@5\nD=A\n@R1\nM=D\nM=M-D\nM=M-D").unwrap();
+        fs::write(
+            dir.join("Prog.cmp"),
+            "|  RAM[0]  |RAM[1] |RAM[1]|\n|     17   |    5  | 0005 |\n|     17   |   -4  | FFFB |\n",
+        )
+        .unwrap();
+
+        let script = "
+// comment
+load Prog.asm,
+output-file Prog.out,
+compare-to Prog.cmp,
+output-list RAM[0]%D2.6.2 RAM[1]%D1.5.1 RAM[1]%X1.4.1;
+
+set RAM[0] 17,   /* block
+ comment */
+repeat 4 {
+  ticktock;
+}
+output;
+repeat 2 { ticktock; }
+output;
+";
+        let report = run_script(script, &dir).unwrap();
+        assert_eq!(
+            "|  RAM[0]  |RAM[1] |RAM[1]|\n|     17   |    5  | 0005 |\n|     17   |   -5  | FFFB |\n",
+            report.output
+        );
+        assert_eq!(
+            Some(Mismatch {
+                line: 3,
+                expected: "|     17   |   -4  | FFFB |".to_string(),
+                actual: "|     17   |   -5  | FFFB |".to_string(),
+            }),
+            report.mismatch
+        );
+        assert_eq!(Some(dir.join("Prog.out")), report.output_file);
+    }
+
+    #[test]
+    fn simple_add_test() {
+        let dir = std::env::temp_dir().join("vmtrans_tst_simple_add_test");
+        fs::create_dir_all(&dir).unwrap();
+        let vm = fs::read_to_string("SimpleAdd.vm").unwrap();
+        let asm = crate::translate(&[("SimpleAdd.vm", &vm)], &Default::default()).unwrap();
+        fs::write(dir.join("SimpleAdd.asm"), asm).unwrap();
+        // projects/07/StackArithmetic/SimpleAdd/SimpleAdd.cmp
+        fs::copy("SimpleAdd.cmp", dir.join("SimpleAdd.cmp")).unwrap();
+
+        // projects/07/StackArithmetic/SimpleAdd/SimpleAdd.tst
+        let script = "
+load SimpleAdd.asm,
+output-file SimpleAdd.out,
+compare-to SimpleAdd.cmp,
+output-list RAM[0]%D2.6.2 RAM[256]%D2.6.2;
+
+set RAM[0] 256,  // initializes the stack pointer
+
+repeat 60 {      // enough cycles to complete the execution
+  ticktock;
+}
+
+output;          // the stack pointer and the stack base
+";
+        let report = run_script(script, &dir).unwrap();
+        assert!(report.passed(), "{:?}", report.mismatch);
+    }
+
+    #[test]
+    fn error_test() {
+        let dir = Path::new(".");
+        let e = run_script("load Prog.asm,\nrepeat 2 {\nticktock;\n}", dir).unwrap_err();
+        assert!(e.to_string().starts_with("cannot read"));
+        let e = run_script("output-list RAM[0]%D1.6.1;\nwhile RAM[0] < 3 {\n}", dir).unwrap_err();
+        assert_eq!(
+            "line 2: unsupported block `while RAM[0] < 3`",
+            e.to_string()
+        );
+        let e = run_script("set RAM[0] x;", dir).unwrap_err();
+        assert_eq!("line 1: invalid value `x`", e.to_string());
+    }
+}
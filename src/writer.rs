@@ -1,253 +1,883 @@
-use std::{
-    io::{BufWriter, Write},
-    str::FromStr,
-};
+use std::collections::HashSet;
 
 use crate::{
+    asm::Asm,
+    assembler::{AValue, Comp, Dest, Jump},
+    command::{ArithOp, Segment, Spanned, VmCommand},
+    error::{SourceSpan, TranslateError},
+    fold::{Folded, Operand},
     parser::CommandType,
+    sourcemap::Origin,
     template::{
-        generate_pop_specified_register_template, generate_pop_specified_register_template_pointer,
+        generate_binary_template, generate_call_template, generate_cmp_bool_d_template,
+        generate_cmp_diff_template, generate_cmp_jump_template, generate_cmp_push_template,
+        generate_eq_diff_template, generate_function_template, generate_goto_template,
+        generate_if_d_goto_template, generate_if_goto_template, generate_if_not_goto_template,
+        generate_init_template, generate_label_template, generate_load_value_template,
+        generate_pop_d_template, generate_pop_specified_register_template,
+        generate_pop_specified_register_template_pointer, generate_pop_static_template,
+        generate_push_constant_template, generate_push_d_template,
         generate_push_specified_register_template,
-        generate_push_specified_register_template_pointer, ADD_ASM, AND_ASM, CMP_CONST_ASM,
-        DEF_LABEL_AMS, FALSE_CMP_LABEL, GOTO_LABEL_AMS, IFGOTO_LABEL_AMS, INIT, LABEL_NAME,
-        NEG_ASM, NOT_ASM, OR_ASM, POP_STATIC_AMS, PUSH_CONST_AMS, PUSH_STATIC_AMS, RET_END_LABEL,
-        RET_FALSE_LABEL, RET_TRUE_LABEL, SUB_ASM, TRUE_CMP_LABEL,
+        generate_push_specified_register_template_pointer, generate_push_static_template,
+        generate_return_template, generate_return_to_r14_template, generate_set_return_template,
+        generate_shared_call_args_template, generate_shared_call_template,
+        generate_stack_top_template, generate_unary_template, CmpLabels, SHARED_CALL_LABEL,
+        SHARED_RETURN_LABEL,
     },
 };
 
-#[derive(strum_macros::EnumString)]
-enum Segment {
-    #[strum(serialize = "constant")]
-    constant,
-    #[strum(serialize = "static")]
+/// How the entries of a segment are addressed.
+enum Addressing {
+    /// the index is the value, nothing is stored
+    Constant,
+    /// a symbol of its own for each file and index
     Static,
-    #[strum(serialize = "local")]
-    Local,
-    #[strum(serialize = "argument")]
-    Arg,
-    #[strum(serialize = "that")]
-    That,
-    #[strum(serialize = "this")]
-    This,
-    #[strum(serialize = "temp")]
-    Temp,
-    #[strum(serialize = "pointer")]
-    Pointer,
+    /// the index plus a fixed address
+    Fixed(u16),
+    /// the index plus the address held by a base register
+    Based(&'static str),
 }
 
 impl Segment {
-    fn write_push_asm<W>(&self, index: i64, f: &mut BufWriter<W>, label: Option<&str>)
-    where
-        W: std::io::Write,
-    {
-        match self {
-            Segment::constant => {
-                let replaced_str = PUSH_CONST_AMS.replace("{}", &index.to_string());
-                f.write_all(replaced_str.as_bytes()).unwrap();
-            }
-            Segment::Static => {
-                let replaced_str = PUSH_STATIC_AMS
-                    .replace("{}", &format!("{}.{}", label.unwrap(), &index.to_string()));
-                f.write_all(replaced_str.as_bytes()).unwrap();
-            }
-            Segment::Local | &Segment::Arg | Segment::That | Segment::This => {
-                f.write_all(
-                    generate_push_specified_register_template(
-                        index as usize,
-                        self.get_register_name(),
-                    )
-                    .as_bytes(),
-                )
-                .unwrap();
-            }
-            Segment::Temp | Segment::Pointer => {
-                f.write_all(
-                    generate_push_specified_register_template_pointer(
-                        index as usize,
-                        self.get_register_name(),
-                    )
-                    .as_bytes(),
-                )
-                .unwrap();
-            }
-        }
-    }
-
-    fn write_pop_asm<W>(&self, index: i64, f: &mut BufWriter<W>, label: Option<&str>)
-    where
-        W: std::io::Write,
-    {
-        match self {
-            Segment::constant => {
-                panic!("cannot pop constant")
-            }
-            Segment::Static => {
-                let replaced_str = POP_STATIC_AMS
-                    .replace("{}", &format!("{}.{}", label.unwrap(), &index.to_string()));
-                f.write_all(replaced_str.as_bytes()).unwrap();
-            }
-            Segment::Local | &Segment::Arg | Segment::That | Segment::This => {
-                f.write_all(
-                    generate_pop_specified_register_template(
-                        index as usize,
-                        self.get_register_name(),
-                    )
-                    .as_bytes(),
-                )
-                .unwrap();
-            }
-            Segment::Temp | Segment::Pointer => {
-                f.write_all(
-                    generate_pop_specified_register_template_pointer(
-                        index as usize,
-                        self.get_register_name(),
-                    )
-                    .as_bytes(),
-                )
-                .unwrap();
-            }
-        }
-    }
-
-    fn get_register_name(&self) -> &str {
+    fn addressing(&self) -> Addressing {
         match self {
-            Segment::constant => todo!(),
-            Segment::Static => todo!(),
-            Segment::Local => "LCL",
-            Segment::Arg => "ARG",
-            Segment::That => "THAT",
-            Segment::This => "THIS",
-            Segment::Temp => "5",
-            Segment::Pointer => "3",
+            Segment::Constant => Addressing::Constant,
+            Segment::Static => Addressing::Static,
+            Segment::Local => Addressing::Based("LCL"),
+            Segment::Arg => Addressing::Based("ARG"),
+            Segment::That => Addressing::Based("THAT"),
+            Segment::This => Addressing::Based("THIS"),
+            Segment::Temp => Addressing::Fixed(5),
+            Segment::Pointer => Addressing::Fixed(3),
+        }
+    }
+
+    fn push_asm(&self, index: u16, filename: &str) -> Asm {
+        match self.addressing() {
+            Addressing::Constant => generate_push_constant_template(index),
+            Addressing::Static => generate_push_static_template(&format!("{}.{}", filename, index)),
+            Addressing::Based(register) => {
+                generate_push_specified_register_template(index, register.into())
+            }
+            Addressing::Fixed(address) => {
+                generate_push_specified_register_template_pointer(index, address.into())
+            }
+        }
+    }
+
+    fn pop_asm(
+        &self,
+        index: u16,
+        filename: &str,
+        span: &SourceSpan,
+    ) -> Result<Asm, TranslateError> {
+        Ok(match self.addressing() {
+            Addressing::Constant => return Err(TranslateError::PopConstant(span.clone())),
+            Addressing::Static => generate_pop_static_template(&format!("{}.{}", filename, index)),
+            Addressing::Based(register) => {
+                generate_pop_specified_register_template(index, register.into())
+            }
+            Addressing::Fixed(address) => {
+                generate_pop_specified_register_template_pointer(index, address.into())
+            }
+        })
+    }
+
+    /// Address of the entry when it does not depend on a base register.
+    fn direct_address(&self, index: u16, filename: &str) -> Option<AValue> {
+        match self.addressing() {
+            Addressing::Static => Some(AValue::Symbol(format!("{}.{}", filename, index))),
+            Addressing::Fixed(address) => Some(AValue::Address(address + index)),
+            Addressing::Constant | Addressing::Based(_) => None,
+        }
+    }
+
+    /// D = segment[index]
+    fn load_d_asm(&self, index: u16, filename: &str) -> Asm {
+        let asm = Asm::new().blank();
+        if let Some(address) = self.direct_address(index, filename) {
+            return asm.at(address).assign(Dest::D, Comp::M);
+        }
+        match (self.addressing(), index) {
+            (Addressing::Based(register), 0) => asm
+                .at(register)
+                .assign(Dest::A, Comp::M)
+                .assign(Dest::D, Comp::M),
+            (Addressing::Based(register), 1) => asm
+                .at(register)
+                .assign(Dest::A, Comp::MPlusOne)
+                .assign(Dest::D, Comp::M),
+            (Addressing::Based(register), _) => asm
+                .at(index)
+                .assign(Dest::D, Comp::A)
+                .at(register)
+                .assign(Dest::A, Comp::MPlusD)
+                .assign(Dest::D, Comp::M),
+            _ => generate_load_value_template(index),
+        }
+    }
+
+    /// segment[index] = value loaded by `load_d`
+    fn move_asm(
+        &self,
+        index: u16,
+        filename: &str,
+        load_d: Asm,
+        span: &SourceSpan,
+    ) -> Result<Asm, TranslateError> {
+        if let Some(address) = self.direct_address(index, filename) {
+            return Ok(load_d.at(address).assign(Dest::M, Comp::D));
+        }
+        Ok(match (self.addressing(), index) {
+            (Addressing::Based(register), 0) => load_d
+                .at(register)
+                .assign(Dest::A, Comp::M)
+                .assign(Dest::M, Comp::D),
+            (Addressing::Based(register), 1) => load_d
+                .at(register)
+                .assign(Dest::A, Comp::MPlusOne)
+                .assign(Dest::M, Comp::D),
+            (Addressing::Based(register), _) => {
+                Asm::new()
+                    .blank()
+                    .at(index)
+                    .assign(Dest::D, Comp::A)
+                    .at(register)
+                    .assign(Dest::D, Comp::MPlusD)
+                    .at("R13")
+                    .assign(Dest::M, Comp::D)
+                    + load_d
+                    + Asm::new()
+                        .at("R13")
+                        .assign(Dest::A, Comp::M)
+                        .assign(Dest::M, Comp::D)
+            }
+            _ => return Err(TranslateError::PopConstant(span.clone())),
+        })
+    }
+
+    /// segment[index] = D
+    fn store_d_asm(
+        &self,
+        index: u16,
+        filename: &str,
+        span: &SourceSpan,
+    ) -> Result<Asm, TranslateError> {
+        if !matches!(self.addressing(), Addressing::Based(_)) || index <= 1 {
+            return self.move_asm(index, filename, Asm::new().blank(), span);
         }
+        // computing the address needs D
+        let load_d = Asm::new().at("R14").assign(Dest::D, Comp::M);
+        Ok(Asm::new().blank().at("R14").assign(Dest::M, Comp::D)
+            + self.move_asm(index, filename, load_d, span)?)
+    }
+}
+
+/// Jump conditions taken when `op` holds and when it does not, swapped if `negated`.
+fn cmp_jumps(op: ArithOp, negated: bool) -> (Jump, Jump) {
+    let (true_cmp, false_cmp) = match op {
+        ArithOp::Eq => (Jump::JEQ, Jump::JNE),
+        ArithOp::Lt => (Jump::JLT, Jump::JGE),
+        ArithOp::Gt => (Jump::JGT, Jump::JLE),
+        _ => unreachable!("{} is not a comparison", op),
+    };
+    if negated {
+        (false_cmp, true_cmp)
+    } else {
+        (true_cmp, false_cmp)
+    }
+}
+
+/// Pops x and leaves a value in D whose sign decides `op`, with y in D.
+fn generate_cmp_diff(op: ArithOp, labels: &CmpLabels) -> Asm {
+    match op {
+        // x - y cannot overflow into a wrong `eq`, only into a wrong sign
+        ArithOp::Eq => generate_eq_diff_template(),
+        _ => generate_cmp_diff_template(labels),
     }
 }
 
-pub struct CodeWriter<W: std::io::Write> {
-    f: BufWriter<W>,
+#[derive(Default)]
+pub struct CodeWriter {
+    /// code written so far
+    asm: Asm,
     logical_op_count: usize,
+    /// return addresses written, calls and jumps to shared subroutines
+    call_count: usize,
     filename: String,
+    /// function being written, labels are scoped to it
+    function: String,
+    /// emit calls to shared subroutines instead of large inline templates
+    size_mode: bool,
+    /// shared subroutines written so far
+    routines: HashSet<String>,
+    /// keep the stack top in D instead of pushing it
+    cache_top: bool,
+    /// the stack top is in D and not in RAM, SP does not count it
+    top_in_d: bool,
 }
 
-impl<W: std::io::Write> CodeWriter<W> {
-    pub fn new(output: BufWriter<W>) -> CodeWriter<W> {
-        CodeWriter {
-            f: output,
-            logical_op_count: 0,
-            filename: String::new(),
-        }
+#[allow(non_snake_case)]
+impl CodeWriter {
+    pub fn new() -> CodeWriter {
+        CodeWriter::default()
     }
 
     pub fn setFileName(&mut self, filename: &str) {
         self.filename = filename.to_string();
     }
 
+    /// Trades speed for code size, see [`crate::Options::optimize_size`].
+    pub fn setSizeMode(&mut self, enabled: bool) {
+        self.size_mode = enabled;
+    }
+
+    /// Keeps the stack top in D between commands, see [`crate::Options::opt_level`].
+    pub fn setStackTopCache(&mut self, enabled: bool) {
+        self.cache_top = enabled;
+    }
+
+    /// Pushes the stack top cached in D, before code which expects the whole stack in RAM.
+    fn spill(&mut self) -> Asm {
+        if !self.top_in_d {
+            return Asm::new();
+        }
+        self.top_in_d = false;
+        Asm::new().blank() + generate_push_d_template()
+    }
+
+    /// Moves the stack top into D, before consuming it.
+    fn fill(&mut self) -> Asm {
+        if self.top_in_d {
+            return Asm::new();
+        }
+        self.top_in_d = true;
+        generate_pop_d_template()
+    }
+
+    /// Marks the start of each command with its origin, see [`crate::sourcemap`].
+    fn write_marker(&mut self, span: &SourceSpan) {
+        self.write_asm(Asm::new().origin(Origin::from_span(span)));
+    }
+
+    /// Appends `asm` to the output.
+    fn write_asm(&mut self, asm: Asm) {
+        self.asm += asm;
+    }
+
     pub fn debug(&mut self) {
-        self.f.write_all(INIT.as_bytes()).unwrap();
+        self.write_asm(generate_init_template())
+    }
+
+    pub fn writeInit(&mut self, entry: &str) {
+        self.write_asm(generate_init_template());
+        self.writeCall(entry, 0)
+    }
+
+    /// Assembly symbol of the VM `label` in the current function, `function$label`.
+    ///
+    /// Labels outside any function are kept as they are.
+    fn mangle_label(&self, label: &str) -> String {
+        if self.function.is_empty() {
+            label.to_string()
+        } else {
+            format!("{}${}", self.function, label)
+        }
+    }
+
+    /// Label of the next return address, `$$RET.caller.n` with the current function as the caller.
+    ///
+    /// `$$` is never part of a VM name, so no user label can clash with it.
+    fn next_return_label(&mut self) -> String {
+        self.call_count += 1;
+        if self.function.is_empty() {
+            format!("$$RET.{}", self.call_count)
+        } else {
+            format!("$$RET.{}.{}", self.function, self.call_count)
+        }
     }
 
-    fn generate_cmp_template(&mut self, command: &str) -> String {
+    /// Labels of the next comparison, unique among all comparisons written.
+    fn next_cmp_labels(&mut self, op: ArithOp) -> CmpLabels {
         self.logical_op_count += 1;
-        let template = CMP_CONST_ASM;
-        let true_label = RET_TRUE_LABEL;
-        let false_label = RET_FALSE_LABEL;
-        let end_label = RET_END_LABEL;
-        let true_cmp_label = TRUE_CMP_LABEL;
-        let false_cmp_label = FALSE_CMP_LABEL;
-
-        let mut replaced_true = true_label.to_string();
-        replaced_true.push_str(&format!("_{}_{}", command, self.logical_op_count));
-        let mut replaced_false = false_label.to_string();
-        replaced_false.push_str(&format!("_{}_{}", command, self.logical_op_count));
-        let mut replaced_end = end_label.to_string();
-        replaced_end.push_str(&format!("_{}_{}", command, self.logical_op_count));
-
-        let (true_cmp, false_cmp) = {
-            if command == "eq" {
-                ("JEQ", "JNE")
-            } else if command == "lt" {
-                ("JLT", "JGE")
-            } else if command == "gt" {
-                ("JGT", "JLE")
-            } else {
-                panic!("not supported command {}", command)
-            }
-        };
+        CmpLabels::new(op, self.logical_op_count)
+    }
 
-        template
-            .replace(true_label, &replaced_true)
-            .replace(false_label, &replaced_false)
-            .replace(end_label, &replaced_end)
-            .replace(true_cmp_label, true_cmp)
-            .replace(false_cmp_label, false_cmp)
-    }
-
-    pub fn writeArithmetic(&mut self, command: &str) {
-        if command == "add" {
-            self.f.write_all(ADD_ASM.as_bytes()).unwrap();
-        } else if command == "sub" {
-            self.f.write_all(SUB_ASM.as_bytes()).unwrap();
-        } else if command == "neg" {
-            self.f.write_all(NEG_ASM.as_bytes()).unwrap();
-        } else if command == "and" {
-            self.f.write_all(AND_ASM.as_bytes()).unwrap();
-        } else if command == "or" {
-            self.f.write_all(OR_ASM.as_bytes()).unwrap();
-        } else if command == "not" {
-            self.f.write_all(NOT_ASM.as_bytes()).unwrap();
+    /// Jumps to the shared subroutine `routine`. On first use its code, generated by `body`, is
+    /// written right here instead, so the jump falls through into it.
+    fn generate_shared_jump(&mut self, routine: &str, body: impl FnOnce(&mut Self) -> Asm) -> Asm {
+        if self.routines.insert(routine.to_string()) {
+            Asm::new().blank().label(routine) + body(self)
         } else {
-            let cmp_asm = self.generate_cmp_template(command);
-            self.f.write_all(cmp_asm.as_bytes()).unwrap();
+            Asm::new().blank().at(routine).jump(Comp::Zero, Jump::JMP)
         }
     }
 
-    pub fn writePushPop(&mut self, command: &CommandType, segment: &str, index: i64) {
-        let seg = Segment::from_str(segment).unwrap();
-        match command {
-            CommandType::C_PUSH => {
-                seg.write_push_asm(index, &mut self.f, Some(&self.filename));
+    /// Pushes the result of the comparison `op` of the two values on top of the stack.
+    fn generate_compare(&mut self, op: ArithOp) -> Asm {
+        let compare = |writer: &mut Self| {
+            let labels = writer.next_cmp_labels(op);
+            let (true_jump, false_jump) = cmp_jumps(op, false);
+            generate_pop_d_template()
+                + generate_cmp_diff_template(&labels)
+                + generate_cmp_push_template(&labels, true_jump, false_jump)
+        };
+        if !self.size_mode {
+            return compare(self);
+        }
+        let routine = format!("$${}", op);
+        let return_label = self.next_return_label();
+        let jump = self.generate_shared_jump(&routine, |writer| {
+            compare(writer) + generate_return_to_r14_template()
+        });
+        generate_set_return_template(&return_label) + jump + Asm::new().label(&return_label)
+    }
+
+    pub fn writeCommand(&mut self, command: &Spanned<VmCommand>) -> Result<(), TranslateError> {
+        self.write_marker(&command.span);
+        self.write_command(command)
+    }
+
+    /// [`Self::writeCommand`] without the marker.
+    fn write_command(&mut self, command: &Spanned<VmCommand>) -> Result<(), TranslateError> {
+        match &command.node {
+            VmCommand::Push { segment, index } => {
+                return self.writePushPop(&CommandType::C_PUSH, *segment, *index, &command.span)
+            }
+            VmCommand::Pop { segment, index } => {
+                return self.writePushPop(&CommandType::C_POP, *segment, *index, &command.span)
+            }
+            VmCommand::Arithmetic(op) => self.writeArithmetic(*op),
+            VmCommand::Label(label) => self.writeLabel(label),
+            VmCommand::Goto(label) => self.writeGoto(label),
+            VmCommand::IfGoto(label) => self.writeIf(label),
+            VmCommand::Function { name, n_vars } => self.writeFunction(name, *n_vars),
+            VmCommand::Call { name, n_args } => self.writeCall(name, *n_args),
+            VmCommand::Return => self.writeReturn(),
+        }
+        Ok(())
+    }
+
+    /// Writes a command produced by the `-O2` folding pass.
+    pub fn writeFolded(&mut self, folded: &Spanned<Folded>) -> Result<(), TranslateError> {
+        // a command left as it is has its own span
+        self.write_marker(&folded.span);
+        let (folded, span) = (&folded.node, &folded.span);
+        // commands use the cache on their own
+        if self.cache_top && !matches!(folded, Folded::Command(_)) {
+            match self.generate_cached_folded(folded) {
+                Some(asm) => {
+                    self.write_asm(asm);
+                    return Ok(());
+                }
+                // the others expect the whole stack in RAM
+                None => {
+                    let spill = self.spill();
+                    self.write_asm(spill);
+                }
+            }
+        }
+        let asm = match folded {
+            Folded::Command(command) => return self.write_command(command),
+            Folded::Push(value) => {
+                generate_load_value_template(*value) + generate_push_d_template()
+            }
+            Folded::Unary(op) => match op {
+                ArithOp::Neg => generate_stack_top_template(Comp::NegM),
+                _ => generate_stack_top_template(Comp::NotM),
+            },
+            Folded::WithConstant { op, value } => match (op, value) {
+                (ArithOp::Add | ArithOp::Sub | ArithOp::Or, 0) | (ArithOp::And, 0xffff) => {
+                    Asm::new()
+                }
+                (ArithOp::Add, 1) | (ArithOp::Sub, 0xffff) => {
+                    generate_stack_top_template(Comp::MPlusOne)
+                }
+                (ArithOp::Sub, 1) | (ArithOp::Add, 0xffff) => {
+                    generate_stack_top_template(Comp::MMinusOne)
+                }
+                (ArithOp::And, 0) => generate_stack_top_template(Comp::Zero),
+                (ArithOp::Or, 0xffff) => generate_stack_top_template(Comp::MinusOne),
+                _ => {
+                    let comp = match op {
+                        ArithOp::Add => Comp::MPlusD,
+                        ArithOp::Sub => Comp::MMinusD,
+                        ArithOp::And => Comp::DAndM,
+                        _ => Comp::DOrM,
+                    };
+                    generate_load_value_template(*value) + generate_stack_top_template(comp)
+                }
+            },
+            Folded::Move {
+                from,
+                segment,
+                index,
+            } => {
+                let load_d = match from {
+                    Operand::Constant(value) => generate_load_value_template(*value),
+                    Operand::Memory(segment, index) => segment.load_d_asm(*index, &self.filename),
+                };
+                segment.move_asm(*index, &self.filename, load_d, span)?
+            }
+            // the sign-safe difference is longer than a call
+            Folded::CompareJump { op, negated, label } if self.size_mode && *op != ArithOp::Eq => {
+                let label = self.mangle_label(label);
+                let jump = if *negated {
+                    generate_if_not_goto_template(&label)
+                } else {
+                    generate_if_goto_template(&label)
+                };
+                self.generate_compare(*op) + jump
+            }
+            Folded::CompareJump { op, negated, label } => {
+                let labels = self.next_cmp_labels(*op);
+                let (true_jump, _) = cmp_jumps(*op, *negated);
+                generate_pop_d_template()
+                    + generate_cmp_diff(*op, &labels)
+                    + generate_cmp_jump_template(&self.mangle_label(label), true_jump)
+            }
+        };
+        self.write_asm(asm);
+        Ok(())
+    }
+
+    /// [`Self::writeFolded`] with the stack top cached in D, None when the cache cannot be used.
+    fn generate_cached_folded(&mut self, folded: &Folded) -> Option<Asm> {
+        let asm = match folded {
+            Folded::Push(value) => {
+                let asm = self.spill() + generate_load_value_template(*value);
+                self.top_in_d = true;
+                asm
+            }
+            Folded::Unary(op) => self.fill() + self.generate_cached_arithmetic(*op),
+            Folded::WithConstant { op, value } => {
+                let asm = Asm::new();
+                let code = match (op, value) {
+                    (ArithOp::Add | ArithOp::Sub | ArithOp::Or, 0) | (ArithOp::And, 0xffff) => {
+                        return Some(asm)
+                    }
+                    (ArithOp::Add, 1) | (ArithOp::Sub, 0xffff) => {
+                        asm.assign(Dest::D, Comp::DPlusOne)
+                    }
+                    (ArithOp::Sub, 1) | (ArithOp::Add, 0xffff) => {
+                        asm.assign(Dest::D, Comp::DMinusOne)
+                    }
+                    (ArithOp::And, 0) => asm.assign(Dest::D, Comp::Zero),
+                    (ArithOp::Or, 0xffff) => asm.assign(Dest::D, Comp::MinusOne),
+                    _ => {
+                        let comp = match op {
+                            ArithOp::Add => Comp::DPlusA,
+                            ArithOp::Sub => Comp::DMinusA,
+                            ArithOp::And => Comp::DAndA,
+                            _ => Comp::DOrA,
+                        };
+                        // @ only loads 15 bits
+                        let load_a = match value {
+                            0x8000.. => asm.at(!value).assign(Dest::A, Comp::NotA),
+                            _ => asm.at(*value),
+                        };
+                        load_a.assign(Dest::D, comp)
+                    }
+                };
+                self.fill() + Asm::new().blank() + code
             }
-            CommandType::C_POP => {
-                seg.write_pop_asm(index, &mut self.f, Some(&self.filename));
+            // moves go through D
+            Folded::Command(_) | Folded::Move { .. } => return None,
+            Folded::CompareJump { .. } if self.size_mode => return None,
+            Folded::CompareJump { op, negated, label } => {
+                let fill = self.fill();
+                self.top_in_d = false;
+                let labels = self.next_cmp_labels(*op);
+                let (true_jump, _) = cmp_jumps(*op, *negated);
+                fill + generate_cmp_diff(*op, &labels)
+                    + generate_cmp_jump_template(&self.mangle_label(label), true_jump)
             }
-            _ => {
-                panic!("not called this command type {:?}", command)
+        };
+        Some(asm)
+    }
+
+    /// Applies `op` to the stack top in D, popping x from RAM for binary operations.
+    fn generate_cached_arithmetic(&mut self, op: ArithOp) -> Asm {
+        let comp = match op {
+            ArithOp::Neg => return Asm::new().assign(Dest::D, Comp::NegD),
+            ArithOp::Not => return Asm::new().assign(Dest::D, Comp::NotD),
+            ArithOp::Add => Comp::DPlusM,
+            ArithOp::Sub => Comp::MMinusD,
+            ArithOp::And => Comp::DAndM,
+            ArithOp::Or => Comp::DOrM,
+            ArithOp::Eq | ArithOp::Gt | ArithOp::Lt => {
+                let labels = self.next_cmp_labels(op);
+                let (true_jump, _) = cmp_jumps(op, false);
+                return generate_cmp_diff(op, &labels)
+                    + generate_cmp_bool_d_template(&labels, true_jump);
             }
+        };
+        Asm::new()
+            .at("SP")
+            .assign(Dest::AM, Comp::MMinusOne)
+            .assign(Dest::D, comp)
+    }
+
+    pub fn writeArithmetic(&mut self, op: ArithOp) {
+        let is_cmp = matches!(op, ArithOp::Eq | ArithOp::Gt | ArithOp::Lt);
+        // shared comparison subroutines take their operands from RAM
+        if self.cache_top && !(self.size_mode && is_cmp) {
+            let asm = self.fill() + self.generate_cached_arithmetic(op);
+            return self.write_asm(asm);
+        }
+        let spill = self.spill();
+        let asm = match op {
+            ArithOp::Add => generate_binary_template(op, Comp::MPlusD),
+            ArithOp::Sub => generate_binary_template(op, Comp::MMinusD),
+            ArithOp::And => generate_binary_template(op, Comp::MAndD),
+            ArithOp::Or => generate_binary_template(op, Comp::MOrD),
+            ArithOp::Neg => generate_unary_template(op, Comp::NegM),
+            ArithOp::Not => generate_unary_template(op, Comp::NotM),
+            ArithOp::Eq | ArithOp::Gt | ArithOp::Lt => self.generate_compare(op),
+        };
+        self.write_asm(spill + asm)
+    }
+
+    /// Pushes or pops an entry of `segment`, `span` locates the command in errors.
+    pub fn writePushPop(
+        &mut self,
+        command: &CommandType,
+        segment: Segment,
+        index: u16,
+        span: &SourceSpan,
+    ) -> Result<(), TranslateError> {
+        if self.cache_top {
+            let asm = match command {
+                CommandType::C_POP => {
+                    let store_d = segment.store_d_asm(index, &self.filename, span)?;
+                    let asm = self.fill() + store_d;
+                    self.top_in_d = false;
+                    asm
+                }
+                _ => {
+                    let asm = self.spill() + segment.load_d_asm(index, &self.filename);
+                    self.top_in_d = true;
+                    asm
+                }
+            };
+            self.write_asm(asm);
+            return Ok(());
         }
+        let asm = match command {
+            CommandType::C_POP => segment.pop_asm(index, &self.filename, span)?,
+            // only push and pop are dispatched here
+            _ => segment.push_asm(index, &self.filename),
+        };
+        self.write_asm(asm);
+        Ok(())
     }
 
-    pub fn writeLabel(&mut self, command: &CommandType, label: &str) {
-        let asm = DEF_LABEL_AMS.replace(LABEL_NAME, label);
-        self.f.write_all(asm.as_bytes()).unwrap();
+    pub fn writeLabel(&mut self, label: &str) {
+        let asm = self.spill() + generate_label_template(&self.mangle_label(label));
+        self.write_asm(asm)
     }
-    pub fn writeGoto(&mut self, command: &CommandType, label: &str) {
-        let asm = GOTO_LABEL_AMS.replace(LABEL_NAME, label);
-        self.f.write_all(asm.as_bytes()).unwrap();
+    pub fn writeGoto(&mut self, label: &str) {
+        let asm = self.spill() + generate_goto_template(&self.mangle_label(label));
+        self.write_asm(asm)
+    }
+    pub fn writeIf(&mut self, label: &str) {
+        let label = self.mangle_label(label);
+        let asm = if self.cache_top {
+            let asm = self.fill() + generate_if_d_goto_template(&label);
+            self.top_in_d = false;
+            asm
+        } else {
+            generate_if_goto_template(&label)
+        };
+        self.write_asm(asm)
     }
-    pub fn writeIf(&mut self, command: &CommandType, label: &str) {
-        let asm = IFGOTO_LABEL_AMS.replace(LABEL_NAME, label);
-        self.f.write_all(asm.as_bytes()).unwrap();
+
+    pub fn writeFunction(&mut self, function_name: &str, n_vars: u16) {
+        self.function = function_name.to_string();
+        let asm = self.spill() + generate_function_template(function_name, n_vars as usize);
+        self.write_asm(asm)
+    }
+
+    pub fn writeCall(&mut self, function_name: &str, n_args: u16) {
+        // each call site needs its own return address label
+        let return_label = self.next_return_label();
+        let spill = self.spill();
+        let asm = if self.size_mode {
+            let args = generate_shared_call_args_template(function_name, n_args, &return_label);
+            let jump =
+                self.generate_shared_jump(SHARED_CALL_LABEL, |_| generate_shared_call_template());
+            args + jump + Asm::new().label(&return_label)
+        } else {
+            generate_call_template(function_name, n_args, &return_label)
+        };
+        self.write_asm(spill + asm)
+    }
+
+    pub fn writeReturn(&mut self) {
+        let spill = self.spill();
+        let asm = if self.size_mode {
+            self.generate_shared_jump(SHARED_RETURN_LABEL, |_| generate_return_template())
+        } else {
+            generate_return_template()
+        };
+        self.write_asm(spill + asm)
     }
-}
 
-impl<W: std::io::Write> Drop for CodeWriter<W> {
-    fn drop(&mut self) {
-        self.f.flush().unwrap();
+    /// Takes the code written so far, with the cached stack top pushed.
+    pub fn finish(&mut self) -> Asm {
+        let spill = self.spill();
+        self.write_asm(spill);
+        std::mem::take(&mut self.asm)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{fs::File, io::BufWriter};
+    use std::fs;
 
     use super::CodeWriter;
+    use crate::{
+        assembler::assemble,
+        command::{ArithOp, Segment},
+        error::{SourceSpan, TranslateError},
+        parser::CommandType,
+        tests::{assert_ram, read, run_vm},
+        translate, Options,
+    };
+
+    fn span() -> SourceSpan {
+        SourceSpan {
+            path: "Main.vm".to_string(),
+            line: 3,
+            column: 1,
+            text: "pop constant 1".to_string(),
+            source_line: "pop constant 1".to_string(),
+        }
+    }
+
+    #[test]
+    fn pop_constant_test() {
+        for cache_top in [false, true] {
+            let mut writer = CodeWriter::new();
+            writer.setStackTopCache(cache_top);
+            match writer.writePushPop(&CommandType::C_POP, Segment::Constant, 1, &span()) {
+                Err(TranslateError::PopConstant(span)) => assert_eq!(3, span.line),
+                other => panic!("expected PopConstant, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn call_return_address_test() {
+        let mut writer = CodeWriter::new();
+        writer.writeCall("Main.fibonacci", 1);
+        writer.writeCall("Main.fibonacci", 1);
+        let actual = writer.finish().to_string();
+        assert!(actual.contains("($$RET.1)"));
+        assert!(actual.contains("($$RET.2)"));
+        // ARG = SP - 5 - nArgs
+        assert!(actual.contains("@6\nD=D-A\n@ARG"));
+    }
+
+    #[test]
+    fn shared_call_test() {
+        let mut writer = CodeWriter::new();
+        writer.setSizeMode(true);
+        writer.writeCall("Main.fibonacci", 1);
+        writer.writeCall("Main.fibonacci", 1);
+        writer.writeReturn();
+        writer.writeReturn();
+        let actual = writer.finish().to_string();
+        // the first use defines the routine, later ones jump to it
+        assert_eq!(1, actual.matches("($$CALL)").count());
+        assert_eq!(1, actual.matches("@$$CALL\n0;JMP").count());
+        assert_eq!(1, actual.matches("($$RETURN)").count());
+        assert_eq!(1, actual.matches("@$$RETURN\n0;JMP").count());
+        assert!(actual.contains("@$$RET.2\nD=A\n@R15\nM=D\n\n@$$CALL\n0;JMP\n($$RET.2)"));
+    }
+
+    #[test]
+    fn return_label_test() {
+        // a user label spelled like a return address of the same function
+        for size_mode in [false, true] {
+            let mut writer = CodeWriter::new();
+            writer.setSizeMode(size_mode);
+            writer.writeFunction("Main.f", 0);
+            writer.writeLabel("ret.1");
+            writer.writeCall("Main.f", 0);
+            writer.writeArithmetic(ArithOp::Eq);
+            let actual = writer.finish().to_string();
+            assert!(actual.contains("(Main.f$ret.1)"));
+            assert!(actual.contains("($$RET.Main.f.1)"));
+            assert!(assemble(&actual).is_ok());
+        }
+    }
+
+    #[test]
+    fn label_scope_test() {
+        let mut writer = CodeWriter::new();
+        writer.writeLabel("START");
+        for function in ["Main.f", "Main.g"] {
+            writer.writeFunction(function, 0);
+            writer.writeLabel("LOOP");
+            writer.writeGoto("LOOP");
+            writer.writeIf("LOOP");
+        }
+        writer.writeArithmetic(ArithOp::Lt);
+        let actual = writer.finish().to_string();
+        assert!(actual.starts_with("\n(START)\n"));
+        for function in ["Main.f", "Main.g"] {
+            let label = format!("{}$LOOP", function);
+            assert_eq!(1, actual.matches(&format!("({})", label)).count());
+            assert_eq!(2, actual.matches(&format!("@{}\n", label)).count());
+        }
+        // comparison labels have a namespace of their own
+        assert!(actual.contains("($$RETURNTRUE.lt.1)"));
+    }
+
+    #[test]
+    fn stack_top_cache_test() {
+        let mut writer = CodeWriter::new();
+        writer.setStackTopCache(true);
+        writer
+            .writePushPop(&CommandType::C_PUSH, Segment::Local, 0, &span())
+            .unwrap();
+        writer
+            .writePushPop(&CommandType::C_PUSH, Segment::Arg, 1, &span())
+            .unwrap();
+        writer.writeArithmetic(ArithOp::Add);
+        writer.writeIf("LOOP");
+        writer
+            .writePushPop(&CommandType::C_PUSH, Segment::Constant, 3, &span())
+            .unwrap();
+        let actual = writer.finish().to_string();
+        // only local 0 goes through RAM, the rest of the stack top stays in D
+        assert_eq!(
+            "\n@LCL\nA=M\nD=M\n\n@SP\nA=M\nM=D\n@SP\nM=M+1\n\n@ARG\nA=M+1\nD=M\n@SP\nAM=M-1\nD=D+M\n\n@LOOP\nD;JNE\n\n@3\nD=A\n\n@SP\nA=M\nM=D\n@SP\nM=M+1\n",
+            actual
+        );
+    }
+
+    #[test]
+    fn init_test() {
+        let mut writer = CodeWriter::new();
+        writer.writeInit("Sys.init");
+        let actual = writer.finish().to_string();
+        assert!(actual.starts_with("\n// SP = 256\n@256\nD=A\n@SP\nM=D\n"));
+        assert!(actual.contains("@Sys.init\n0;JMP"));
+        assert!(!actual.contains("@400"));
+    }
 
     #[test]
     fn work_test() {
-        let file = File::create("dump.asm").unwrap();
-        let mut writer = CodeWriter::new(BufWriter::new(file));
-        writer.writePushPop(&crate::parser::CommandType::C_PUSH, "constant", 7);
-        writer.writePushPop(&crate::parser::CommandType::C_PUSH, "constant", 8);
-        writer.writeArithmetic("add");
+        let mut writer = CodeWriter::new();
+        writer
+            .writePushPop(&CommandType::C_PUSH, Segment::Constant, 7, &span())
+            .unwrap();
+        writer
+            .writePushPop(&CommandType::C_PUSH, Segment::Constant, 8, &span())
+            .unwrap();
+        writer.writeArithmetic(ArithOp::Add);
+        fs::write("dump.asm", writer.finish().to_string()).unwrap();
+    }
+
+    #[test]
+    fn compare_overflow_test() {
+        // x - y overflows for operands of different signs
+        let options = Options {
+            debug: true,
+            ..Default::default()
+        };
+        let source = "push constant 2\nneg\npush constant 32767\ngt\npush constant 32767\npush constant 2\nneg\ngt\npush constant 2\nneg\npush constant 32767\nlt\n";
+        let emulator = run_vm(&[("Compare.vm", source)], &options, &[]);
+        assert_ram(&emulator, &[(0, 259), (256, 0), (257, -1), (258, -1)]);
+    }
+
+    #[test]
+    fn optimize_size_test() {
+        let source = read("StackTest.vm");
+        for opt_level in 0..=3 {
+            let options = Options {
+                debug: true,
+                opt_level,
+                ..Default::default()
+            };
+            let inline = assemble(&translate(&[("StackTest.vm", &source)], &options).unwrap());
+            let options = Options {
+                optimize_size: true,
+                ..options
+            };
+            let shared = assemble(&translate(&[("StackTest.vm", &source)], &options).unwrap());
+            // -O2 and above fold the comparisons of constants away
+            if opt_level < 2 {
+                assert!(shared.unwrap().len() < inline.unwrap().len());
+            }
+
+            let emulator = run_vm(&[("StackTest.vm", &source)], &options, &[]);
+            assert_ram(
+                &emulator,
+                &[
+                    (0, 266),
+                    (256, -1),
+                    (257, 0),
+                    (258, 0),
+                    (262, -1),
+                    (265, -91),
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn template_like_label_test() {
+        // labels named like the placeholders the templates once used
+        let source = "push constant 3\npop temp 0\nlabel LABEL_NAME\npush temp 0\npush constant 1\nsub\npop temp 0\npush temp 0\npush constant 0\ngt\nif-goto LABEL_NAME\npush temp 0\npush constant 0\neq\nif-goto TRUECMPLABEL\ngoto RETURNTRUE\nlabel TRUECMPLABEL\npush constant 7\npop temp 1\nlabel RETURNTRUE\n";
+        for optimize_size in [false, true] {
+            for opt_level in 0..=3 {
+                let options = Options {
+                    debug: true,
+                    opt_level,
+                    optimize_size,
+                    ..Default::default()
+                };
+                let emulator = run_vm(&[("Label.vm", source)], &options, &[]);
+                assert_ram(&emulator, &[(0, 256), (5, 0), (6, 7)]);
+            }
+        }
+    }
+
+    #[test]
+    fn label_scope_run_test() {
+        // the same label in two functions
+        let source = "function Main.f 0\nlabel END\npush constant 1\nreturn\nfunction Sys.init 0\ncall Main.f 0\npop temp 0\ngoto END\npush constant 2\npop temp 0\nlabel END\ngoto END\n";
+        for opt_level in 0..=3 {
+            let options = Options {
+                entry: Some("Sys.init".to_string()),
+                opt_level,
+                ..Default::default()
+            };
+            let emulator = run_vm(&[("Main.vm", source)], &options, &[]);
+            assert_ram(&emulator, &[(5, 1)]);
+        }
+    }
+
+    #[test]
+    fn compare_jump_test() {
+        // each jump not taken adds its bit to temp 0, -2 and 32767 overflow x - y
+        let source = "push constant 2\nneg\npop temp 1\npush constant 32767\npop temp 2\npush constant 7\npop temp 3\npush temp 1\npush temp 2\ngt\nif-goto A\npush constant 1\npop temp 0\nlabel A\npush temp 1\npush temp 2\nlt\nif-goto B\npush temp 0\npush constant 2\nadd\npop temp 0\nlabel B\npush temp 3\npush constant 8\neq\nnot\nif-goto C\npush temp 0\npush constant 4\nadd\npop temp 0\nlabel C\npush temp 2\npush temp 1\ngt\nnot\nif-goto D\npush temp 0\npush constant 8\nadd\npop temp 0\nlabel D\n";
+        for opt_level in 0..=3 {
+            let options = Options {
+                debug: true,
+                opt_level,
+                ..Default::default()
+            };
+            if opt_level >= 2 {
+                // jumps directly, without pushing the comparison result
+                let asm = translate(&[("Jump.vm", source)], &options).unwrap();
+                assert!(!asm.contains("RETURNTRUE"));
+            }
+            let emulator = run_vm(&[("Jump.vm", source)], &options, &[]);
+            assert_ram(&emulator, &[(0, 256), (5, 9)]);
+        }
     }
 }
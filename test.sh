@@ -1,17 +1,17 @@
 #!/bin/bash
 set -e
 
-CPUEmulatorPath="../../tools/CPUEmulator.sh"
-
 cargo build
 BIN_PATH="./target/debug/vmtrans"
 
+# the .tst scripts are run by the built-in CPU emulator, see `vmtrans test --help`
+
 TEST_NAME=("SimpleAdd" "StackTest")
 for test_case in "${TEST_NAME[@]}"
 do
     echo "###### TEST_CASE: $test_case #######"
     $BIN_PATH -i ./$test_case.vm -o ../07/StackArithmetic/$test_case/$test_case.asm
-    $CPUEmulatorPath ../07/StackArithmetic/$test_case/$test_case.tst
+    $BIN_PATH test ../07/StackArithmetic/$test_case/$test_case.tst
 done
 
 TEST_NAME=("BasicTest" "PointerTest" "StaticTest")
@@ -19,7 +19,7 @@ for test_case in "${TEST_NAME[@]}"
 do
     echo "###### TEST_CASE: $test_case #######"
     $BIN_PATH -i ./$test_case.vm -o ../07/MemoryAccess/$test_case/$test_case.asm
-    $CPUEmulatorPath ../07/MemoryAccess/$test_case/$test_case.tst
+    $BIN_PATH test ../07/MemoryAccess/$test_case/$test_case.tst
 done
 
 TEST_NAME=("BasicLoop" "FibonacciSeries")
@@ -27,7 +27,7 @@ for test_case in "${TEST_NAME[@]}"
 do
     echo "###### TEST_CASE: $test_case #######"
     $BIN_PATH -i ./$test_case.vm -o ../08/ProgramFlow/$test_case/$test_case.asm
-    $CPUEmulatorPath ../08/ProgramFlow/$test_case/$test_case.tst
+    $BIN_PATH test ../08/ProgramFlow/$test_case/$test_case.tst
 done
 
 TEST_NAME=("SimpleFunction")
@@ -35,6 +35,31 @@ for test_case in "${TEST_NAME[@]}"
 do
     echo "###### TEST_CASE: $test_case #######"
     $BIN_PATH -i ./$test_case.vm -o ../08/FunctionCalls/$test_case/$test_case.asm
-    $CPUEmulatorPath ../08/FunctionCalls/$test_case/$test_case.tst
+    $BIN_PATH test ../08/FunctionCalls/$test_case/$test_case.tst
 done
 
+
+# whole programs: translate the directory, bootstrap code calls Sys.init
+TEST_NAME=("FibonacciElement" "StaticsTest" "NestedCall")
+for test_case in "${TEST_NAME[@]}"
+do
+    echo "###### TEST_CASE: $test_case #######"
+    $BIN_PATH -i ../08/FunctionCalls/$test_case -o ../08/FunctionCalls/$test_case/$test_case.asm
+    $BIN_PATH test ../08/FunctionCalls/$test_case/$test_case.tst
+done
+
+# .hack instruction counts without and with --optimize-size
+OUT=$(mktemp)
+TEST_NAME=("BasicLoop.vm" "SimpleFunction.vm" "FibonacciElement" "StaticsTest" "NestedCall")
+for test_case in "${TEST_NAME[@]}"
+do
+    for level in 0 2
+    do
+        $BIN_PATH -i ./$test_case -o $OUT --emit hack -O$level
+        plain=$(wc -l < $OUT)
+        $BIN_PATH -i ./$test_case -o $OUT --emit hack -O$level --optimize-size
+        shared=$(wc -l < $OUT)
+        echo "$test_case -O$level: $plain -> $shared"
+    done
+done
+rm $OUT